@@ -0,0 +1,362 @@
+use std::{
+    collections::VecDeque,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{Receiver, Sender};
+use rodio::{
+    cpal::{self, traits::HostTrait},
+    DeviceTrait, OutputStream, OutputStreamHandle, Source,
+};
+
+use fm::synth::{Levels, Meter, Patch, Player, PortUpdate, Sequence, SynthInputEvent, SAMPLE_RATE};
+
+/// Print the name of every audio output device.
+pub fn list_devices() -> Result<(), String> {
+    let devices = cpal::default_host()
+        .output_devices()
+        .map_err(|e| e.to_string())?;
+    for device in devices {
+        println!("{}", device.name().map_err(|e| e.to_string())?);
+    }
+    Ok(())
+}
+
+/// Open an audio output stream on the first device whose name contains `device` (case
+/// insensitive), or on the default device.
+pub fn open_output(device: Option<&str>) -> Result<(OutputStream, OutputStreamHandle), String> {
+    let name = match device {
+        Some(name) => name.to_lowercase(),
+        None => return OutputStream::try_default().map_err(|e| e.to_string()),
+    };
+    let device = cpal::default_host()
+        .output_devices()
+        .map_err(|e| e.to_string())?
+        .find(|d| {
+            d.name()
+                .map(|n| n.to_lowercase().contains(&name))
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| format!("no audio output device matching {}", name))?;
+    OutputStream::try_from_device(&device).map_err(|e| e.to_string())
+}
+
+/// Frames computed at a time. Live input is applied about one block after it is sent, at the
+/// frame matching when it was sent, and played sequences at their exact frame.
+const BLOCK_FRAMES: usize = 256;
+/// Duration of one block.
+const BLOCK_DURATION: Duration =
+    Duration::from_nanos(BLOCK_FRAMES as u64 * 1_000_000_000 / SAMPLE_RATE as u64);
+/// Live input queued for one block without allocating.
+const LIVE_QUEUE: usize = 64;
+
+/// Statistics about the audio thread, reported periodically.
+#[derive(Debug, Clone, Copy)]
+pub struct AudioStatus {
+    /// Time spent computing audio, as a fraction of the time that audio plays for.
+    pub cpu_load: f32,
+    /// Voices playing a note, see `Player::voice_activity`.
+    pub active_voices: usize,
+    pub total_voices: usize,
+    /// Output levels since the last report.
+    pub levels: Levels,
+}
+
+/// Changes to the playing patch, sent to the audio thread.
+pub enum AudioCommand {
+    /// Crossfade to a new patch
+    Swap(Patch),
+    /// Change a port of the playing patch. Must already have been checked against the patch
+    /// definition.
+    UpdatePort(PortUpdate),
+}
+
+/// Live input for the audio thread, with the time it was sent.
+pub struct Stamped<T> {
+    sent: Instant,
+    value: T,
+}
+
+impl<T> Stamped<T> {
+    pub fn now(value: T) -> Self {
+        Stamped {
+            sent: Instant::now(),
+            value,
+        }
+    }
+
+    /// Frame of a block rendered at `now` at which to apply this. Input is delayed by one block
+    /// so that it keeps its timing, rather than all of it landing at the start of the block.
+    fn frame(&self, now: Instant) -> usize {
+        let age = now.saturating_duration_since(self.sent).as_secs_f64() * SAMPLE_RATE as f64;
+        BLOCK_FRAMES - 1 - (age as usize).min(BLOCK_FRAMES - 1)
+    }
+}
+
+/// Pop the front of `queue` if it is due at `frame`.
+fn pop_due<T>(queue: &mut VecDeque<(usize, T)>, frame: usize) -> Option<T> {
+    match queue.front() {
+        Some(&(due, _)) if due <= frame => queue.pop_front().map(|(_, value)| value),
+        _ => None,
+    }
+}
+
+/// Plays patches through rodio, taking note events and patch changes from channels. Silent
+/// until the first patch arrives. Replaced patches are sent back over `retired_tx`, to be freed
+/// off the audio thread. Sends an `AudioStatus` over `status_tx` about every `status_interval`,
+/// dropping it if the receiver is not keeping up.
+pub struct PatchSource {
+    player: Player,
+    event_rx: Receiver<Stamped<SynthInputEvent>>,
+    sequence: Option<Sequence>,
+    command_rx: Receiver<Stamped<AudioCommand>>,
+    status_tx: Sender<AudioStatus>,
+    status_frames: usize,
+    // live input of the current block, in order, with the frame each is applied at
+    commands: VecDeque<(usize, AudioCommand)>,
+    events: VecDeque<(usize, SynthInputEvent)>,
+    // interleaved samples of the current block, and the position of the next one to play
+    block: Vec<f32>,
+    position: usize,
+    // accumulated since the last status report
+    frames: usize,
+    busy: Duration,
+    meter: Meter,
+}
+
+impl PatchSource {
+    pub fn new(
+        event_rx: Receiver<Stamped<SynthInputEvent>>,
+        command_rx: Receiver<Stamped<AudioCommand>>,
+        retired_tx: Sender<Patch>,
+        status_tx: Sender<AudioStatus>,
+        status_interval: Duration,
+    ) -> Self {
+        let mut player = Player::new();
+        player.retire_to(retired_tx);
+        PatchSource {
+            player,
+            event_rx,
+            sequence: None,
+            command_rx,
+            status_tx,
+            status_frames: ((status_interval.as_secs_f64() * SAMPLE_RATE as f64).round() as usize)
+                .max(1),
+            commands: VecDeque::with_capacity(LIVE_QUEUE),
+            events: VecDeque::with_capacity(LIVE_QUEUE),
+            block: vec![0.0; BLOCK_FRAMES * 2],
+            position: BLOCK_FRAMES * 2,
+            frames: 0,
+            busy: Duration::ZERO,
+            meter: Meter::new(),
+        }
+    }
+
+    /// Play `sequence` on top of the live events, each event at the start of its frame.
+    pub fn play(&mut self, sequence: Sequence) {
+        self.sequence = Some(sequence);
+    }
+
+    fn next_block(&mut self) {
+        let start = Instant::now();
+        for command in self.command_rx.try_iter() {
+            self.commands
+                .push_back((command.frame(start), command.value));
+        }
+        for event in self.event_rx.try_iter() {
+            self.events.push_back((event.frame(start), event.value));
+        }
+
+        // render up to the next input due in this block, then apply it
+        let mut frame = 0;
+        while frame < BLOCK_FRAMES {
+            while let Some(command) = pop_due(&mut self.commands, frame) {
+                match command {
+                    AudioCommand::Swap(patch) => self.player.swap(patch),
+                    AudioCommand::UpdatePort(update) => {
+                        // already checked, so this can not fail
+                        let _ = self.player.update_port(&update);
+                    }
+                }
+            }
+            while let Some(event) = pop_due(&mut self.events, frame) {
+                self.player.handle_event(event);
+            }
+            let mut end = BLOCK_FRAMES;
+            if let Some(sequence) = &mut self.sequence {
+                for timed in sequence.due() {
+                    self.player.handle_event(timed.event.clone());
+                }
+                if let Some(next) = sequence.frames_to_next() {
+                    end = end.min(frame + next.min(BLOCK_FRAMES as u64) as usize);
+                }
+            }
+            if let Some(&(due, _)) = self.commands.front() {
+                end = end.min(due);
+            }
+            if let Some(&(due, _)) = self.events.front() {
+                end = end.min(due);
+            }
+
+            self.player.fill_block(&mut self.block[frame * 2..end * 2]);
+            if let Some(sequence) = &mut self.sequence {
+                sequence.advance((end - frame) as u64);
+            }
+            frame = end;
+        }
+        self.busy += start.elapsed();
+
+        self.meter.add_block(&self.block);
+        self.frames += BLOCK_FRAMES;
+        if self.frames >= self.status_frames {
+            self.report_status();
+        }
+    }
+
+    fn report_status(&mut self) {
+        let played = Duration::from_secs_f64(self.frames as f64 / SAMPLE_RATE as f64);
+        let (active_voices, total_voices) = self.player.voice_activity();
+        let _ = self.status_tx.try_send(AudioStatus {
+            cpu_load: self.busy.as_secs_f32() / played.as_secs_f32(),
+            active_voices,
+            total_voices,
+            levels: self.meter.take(),
+        });
+        self.frames = 0;
+        self.busy = Duration::ZERO;
+    }
+}
+
+impl Iterator for PatchSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position == self.block.len() {
+            self.next_block();
+            self.position = 0;
+        }
+        let sample = self.block[self.position];
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+impl Source for PatchSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<std::time::Duration> {
+        None
+    }
+}
+
+/// Play `source` in real time from a background thread without an audio device, discarding the
+/// samples.
+pub fn play_headless(mut source: PatchSource) {
+    thread::spawn(move || {
+        let mut deadline = Instant::now();
+        loop {
+            for _ in 0..BLOCK_FRAMES * 2 {
+                source.next();
+            }
+            deadline += BLOCK_DURATION;
+            if let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel::unbounded;
+
+    use fm::synth::{PatchDefinition, TimedEvent};
+
+    use super::*;
+
+    /// Source playing a sine patch, and the channels to send it live input.
+    fn source() -> (
+        PatchSource,
+        Sender<Stamped<SynthInputEvent>>,
+        Sender<Stamped<AudioCommand>>,
+    ) {
+        let def: PatchDefinition =
+            serde_json::from_str(include_str!("../testdata/patches/sine.json")).unwrap();
+        let (event_tx, event_rx) = unbounded();
+        let (command_tx, command_rx) = unbounded();
+        let (retired_tx, _) = unbounded();
+        let (status_tx, _) = unbounded();
+        let source = PatchSource::new(
+            event_rx,
+            command_rx,
+            retired_tx,
+            status_tx,
+            Duration::from_secs(1),
+        );
+        // sent long enough ago to be applied at the start of the first block
+        command_tx
+            .send(Stamped {
+                sent: Instant::now() - BLOCK_DURATION,
+                value: AudioCommand::Swap(Patch::new(&def, 1).unwrap()),
+            })
+            .unwrap();
+        (source, event_tx, command_tx)
+    }
+
+    /// Index of the first audible frame of the next `frames` frames.
+    fn first_sound(source: &mut PatchSource, frames: usize) -> usize {
+        let left: Vec<f32> = source.take(frames * 2).step_by(2).collect();
+        left.iter().position(|&x| x != 0.0).unwrap()
+    }
+
+    #[test]
+    fn played_events_start_on_their_frame() {
+        let (mut source, _event_tx, _command_tx) = source();
+        // the note starts partway through the second block
+        let start = BLOCK_FRAMES + 100;
+        source.play(Sequence::new(vec![TimedEvent {
+            sample: start as u64,
+            event: SynthInputEvent::key_down(69),
+        }]));
+
+        let first_sound = first_sound(&mut source, BLOCK_FRAMES * 4);
+        // the first sample of the note is at phase 0, so it may still be silent
+        assert!(
+            (start..=start + 1).contains(&first_sound),
+            "{}",
+            first_sound
+        );
+    }
+
+    #[test]
+    fn live_events_keep_their_timing_within_a_block() {
+        let (mut source, event_tx, _command_tx) = source();
+        // sent half a block before the block is rendered, so played halfway through it
+        event_tx
+            .send(Stamped {
+                sent: Instant::now() - BLOCK_DURATION / 2,
+                value: SynthInputEvent::key_down(69),
+            })
+            .unwrap();
+
+        let first_sound = first_sound(&mut source, BLOCK_FRAMES);
+        let middle = BLOCK_FRAMES / 2;
+        // earlier only if the test was held up between sending the event and rendering
+        assert!(
+            (middle - 20..=middle + 1).contains(&first_sound),
+            "{}",
+            first_sound
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use std::{net::IpAddr, path::PathBuf};
+
+use clap::{builder::RangedU64ValueParser, Parser, Subcommand};
+
+use fm::synth::DEFAULT_VOICES;
+
+/// Node based FM synthesizer engine. Plays patches sent from the node editor over a websocket,
+/// driven by a MIDI keyboard.
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// MIDI input port to use, by index or (part of) its name. If not given and there are more
+    /// than two ports, the port is chosen interactively.
+    #[arg(long, value_name = "NAME|INDEX", conflicts_with_all = ["no_midi", "midi_file"])]
+    pub midi_port: Option<String>,
+
+    /// Run without MIDI input
+    #[arg(long, conflicts_with = "midi_file")]
+    pub no_midi: bool,
+
+    /// Play a Standard MIDI File instead of using a MIDI input port
+    #[arg(long, value_name = "FILE")]
+    pub midi_file: Option<PathBuf>,
+
+    /// Address the websocket server listens on
+    #[arg(long, default_value = "127.0.0.1")]
+    pub bind: IpAddr,
+
+    /// Port the websocket server listens on
+    #[arg(long, default_value_t = 8080)]
+    pub port: u16,
+
+    /// Number of notes that can sound at once
+    #[arg(
+        long,
+        global = true,
+        default_value_t = DEFAULT_VOICES,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub voices: usize,
+
+    /// Patch to load at startup. The file is reloaded whenever it changes.
+    #[arg(long, value_name = "FILE")]
+    pub patch: Option<PathBuf>,
+
+    /// Do not reload the startup patch when the file changes
+    #[arg(long, requires = "patch")]
+    pub no_watch: bool,
+
+    /// Audio output device, by (part of) its name. Uses the default device if not given.
+    #[arg(long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    /// Run without an audio device, printing the output levels once a second instead
+    #[arg(long, conflicts_with = "device")]
+    pub headless: bool,
+
+    /// List MIDI input ports and exit
+    #[arg(long)]
+    pub list_midi_ports: bool,
+
+    /// List audio output devices and exit
+    #[arg(long)]
+    pub list_devices: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Render a patch to a stereo WAV file without MIDI or an audio device
+    Render {
+        /// Patch JSON to render
+        patch: PathBuf,
+        /// WAV file to write
+        out: PathBuf,
+        /// Notes to play, either a Standard MIDI File (.mid) or a JSON list of
+        /// {"key", "start", "duration"} objects. Holds middle C for one second if not given.
+        script: Option<PathBuf>,
+    },
+}
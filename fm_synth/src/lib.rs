@@ -0,0 +1,30 @@
+//! Node based FM synthesizer engine.
+//!
+//! The engine is independent of any audio or MIDI backend. A patch is loaded from its JSON
+//! definition, driven with [`SynthInputEvent`](synth::SynthInputEvent)s and rendered into blocks
+//! of interleaved stereo samples:
+//!
+//! ```
+//! use fm::synth::{Patch, PatchDefinition, SynthInputEvent, DEFAULT_VOICES};
+//!
+//! let def: PatchDefinition = serde_json::from_str(r#"{
+//!     "nodes": [{
+//!         "type": "sinosc",
+//!         "inputs": {
+//!             "freq": { "mult": 1.0, "bias": 0.0, "link": 0 },
+//!             "phase": { "mult": 1.0, "bias": 0.0 },
+//!             "vol": { "mult": 1.0, "bias": 0.0, "link": 1 },
+//!             "feedback": { "mult": 1.0, "bias": 0.0 }
+//!         },
+//!         "outputs": { "out": { "link": 2 } }
+//!     }],
+//!     "io": { "freq": 0, "gate": 1, "lchan": 2, "rchan": 2 }
+//! }"#).unwrap();
+//!
+//! let mut patch = Patch::new(&def, DEFAULT_VOICES).unwrap();
+//! patch.handle_event(SynthInputEvent::key_down(69));
+//! let mut block = [0.0; 512];
+//! patch.fill_block(&mut block);
+//! ```
+
+pub mod synth;
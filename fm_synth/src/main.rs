@@ -1,73 +1,220 @@
-use std::{
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
-    time::Duration,
-};
+use std::{net::SocketAddr, process, thread, time::Duration};
 
-use crossbeam_channel::unbounded;
-use rodio::{OutputStream, Sink, Source};
+use clap::Parser;
+use crossbeam_channel::{bounded, unbounded, Receiver};
+use rodio::Sink;
 
-use midi::{get_midi_input, parse_midi};
-use server::{start_websocket_server, ClientRequest};
+use audio::{
+    list_devices, open_output, play_headless, AudioCommand, AudioStatus, PatchSource, Stamped,
+};
+use cli::{Cli, Command};
+use midi::{get_midi_input, list_midi_ports, parse_midi};
+use midi_file::load_midi_file;
+use patch_file::{load_patch, watch_patch};
+use server::{
+    start_websocket_server, Broadcaster, ClientRequest, ErrorCode, ErrorDetails, Request,
+    ServerMessage,
+};
 
-use crate::synth::Patch;
+use fm::synth::{waveforms, Patch, Sequence, WAVEFORM_FREQ};
 
+mod audio;
+mod cli;
 mod midi;
+mod midi_file;
+mod offline;
+mod patch_file;
 mod server;
-mod synth;
+
+/// Replaced patches waiting to be freed.
+const RETIRED_QUEUE: usize = 8;
+/// How often audio statistics are sent to websocket clients...
+const STATUS_INTERVAL: Duration = Duration::from_millis(100);
+/// ...or printed when running headless.
+const HEADLESS_STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Pass audio thread statistics on to every websocket client, and print them if `log` is set.
+fn report_status(status_rx: Receiver<AudioStatus>, broadcaster: Broadcaster, log: bool) {
+    thread::spawn(move || {
+        for status in status_rx {
+            let levels = status.levels;
+            if log {
+                println!(
+                    "{}/{} voices, cpu {:.0}%, {}",
+                    status.active_voices,
+                    status.total_voices,
+                    status.cpu_load * 100.0,
+                    levels
+                );
+            }
+            broadcaster.send(&ServerMessage::VoiceActivity {
+                active: status.active_voices,
+                total: status.total_voices,
+            });
+            broadcaster.send(&ServerMessage::CpuLoad {
+                load: status.cpu_load,
+            });
+            broadcaster.send(&ServerMessage::Levels(levels));
+            if levels.clipped() > 0 {
+                broadcaster.send(&ServerMessage::Clipping {
+                    clipped: levels.clipped(),
+                    peak: levels.left.peak.max(levels.right.peak),
+                });
+            }
+        }
+    });
+}
+
+/// Print an error and exit with a failure status.
+fn exit_with<T>(error: String) -> T {
+    eprintln!("{}", error);
+    process::exit(1);
+}
 
 fn main() {
+    let cli = Cli::parse();
+
+    if cli.list_midi_ports {
+        list_midi_ports().unwrap_or_else(exit_with);
+        return;
+    }
+    if cli.list_devices {
+        list_devices().unwrap_or_else(exit_with);
+        return;
+    }
+
+    if let Some(Command::Render {
+        patch,
+        out,
+        script,
+    }) = &cli.command
+    {
+        offline::render_to_wav(patch, out, script.as_deref(), cli.voices)
+            .unwrap_or_else(exit_with);
+        return;
+    }
+
     // Channel to send midi events to synth audio `Source`
     let (synth_event_tx, synth_event_rx) = unbounded();
     let (websocket_tx, websocket_rx) = unbounded();
 
-    start_websocket_server(websocket_tx);
-
-    // setup midi input
-    let (midi_in, port) = get_midi_input().unwrap();
-    let _connection = midi_in
-        .connect(
-            &port,
-            "fm_synth",
-            move |_, message, _| {
-                if let Some(event) = parse_midi(message) {
-                    // Send event over channel
-                    synth_event_tx.send(event).unwrap();
+    let broadcaster =
+        start_websocket_server(SocketAddr::new(cli.bind, cli.port), websocket_tx.clone());
+
+    // setup midi input, either playing a file or from a live port
+    let mut _connection = None;
+    let mut sequence = None;
+    if let Some(path) = &cli.midi_file {
+        // played by the audio thread, so every note starts on its exact sample
+        let events = load_midi_file(path).unwrap_or_else(exit_with);
+        sequence = Some(Sequence::new(events));
+    } else if !cli.no_midi {
+        let (midi_in, port) = get_midi_input(cli.midi_port.as_deref()).unwrap_or_else(exit_with);
+        _connection = Some(
+            midi_in
+                .connect(
+                    &port,
+                    "fm_synth",
+                    move |_, message, _| {
+                        if let Some(event) = parse_midi(message) {
+                            // Send event over channel
+                            synth_event_tx.send(Stamped::now(event)).unwrap();
+                        }
+                    },
+                    (),
+                )
+                .expect("couldnt connect"),
+        );
+    }
+
+    // A single source plays for the whole session, new patches are sent to it to swap in
+    let (audio_tx, audio_rx) = unbounded();
+    // and replaced patches come back to be freed here, rather than on the audio thread
+    let (retired_tx, retired_rx) = bounded(RETIRED_QUEUE);
+    thread::spawn(move || retired_rx.iter().for_each(drop));
+    let (status_tx, status_rx) = bounded(16);
+    let status_interval = if cli.headless {
+        HEADLESS_STATUS_INTERVAL
+    } else {
+        STATUS_INTERVAL
+    };
+    let mut source = PatchSource::new(
+        synth_event_rx,
+        audio_rx,
+        retired_tx,
+        status_tx,
+        status_interval,
+    );
+    if let Some(sequence) = sequence {
+        source.play(sequence);
+    }
+    let mut _output = None;
+    if cli.headless {
+        play_headless(source);
+    } else {
+        let (stream, handle) = open_output(cli.device.as_deref()).unwrap_or_else(exit_with);
+        let sink = Sink::try_new(&handle).unwrap();
+        sink.append(source);
+        // both must be kept alive for the audio to keep playing
+        _output = Some((stream, sink));
+    }
+    report_status(status_rx, broadcaster, cli.headless);
+
+    // The initial patch, and any later changes to it, go through the same path as patches sent
+    // by the editor
+    if let Some(path) = &cli.patch {
+        let patch_def = load_patch(path).unwrap_or_else(exit_with);
+        websocket_tx
+            .send(ClientRequest::UpdatePatch(patch_def).into())
+            .unwrap();
+        if !cli.no_watch {
+            watch_patch(path.clone(), websocket_tx);
+        }
+    }
+
+    // definition of the playing patch, kept up to date with port changes
+    let mut current_def = None;
+    let no_patch = || ErrorDetails::new(ErrorCode::NoPatch, "no patch loaded");
+    while let Ok(Request { request, reply }) = websocket_rx.recv() {
+        let result = match request {
+            ClientRequest::UpdatePatch(patch_def) => {
+                println!("Received patch");
+                match Patch::new(&patch_def, cli.voices) {
+                    Ok(patch) => {
+                        audio_tx
+                            .send(Stamped::now(AudioCommand::Swap(patch)))
+                            .unwrap();
+                        current_def = Some(patch_def);
+                        Ok(None)
+                    }
+                    Err(e) => Err(e.into()),
                 }
+            }
+            ClientRequest::SetPort(update) => match &mut current_def {
+                Some(def) => def
+                    .update_port(&update)
+                    .map(|()| {
+                        audio_tx
+                            .send(Stamped::now(AudioCommand::UpdatePort(update)))
+                            .unwrap();
+                        None
+                    })
+                    .map_err(ErrorDetails::from),
+                None => Err(no_patch()),
             },
-            (),
-        )
-        .expect("couldnt connect");
-
-    let (_stream, handle) = OutputStream::try_default().unwrap();
-    let sink = Sink::try_new(&handle).unwrap();
-
-    // Index of the currently active patch. All other patches periodically check if
-    // their index equals this, and stop/destroy themselves if not.
-    let active_patch_number = Arc::new(AtomicUsize::new(0));
-
-    while let Ok(req) = websocket_rx.recv() {
-        if let ClientRequest::UpdatePatch(patch_def) = req {
-            println!("Received patch");
-            let active_patch_number = active_patch_number.clone();
-            active_patch_number.fetch_add(1, Ordering::SeqCst);
-
-            let patch = Patch::new(
-                patch_def,
-                synth_event_rx.clone(),
-                active_patch_number.load(Ordering::SeqCst),
-            )
-            .stoppable()
-            .periodic_access(Duration::from_millis(100), move |src| {
-                // detect if this patch is stale and stop
-                if src.inner().index != active_patch_number.load(Ordering::SeqCst) {
-                    println!("Stopping patch {}", src.inner().index);
-                    src.stop();
-                }
-            });
-            sink.append(patch);
+            ClientRequest::RequestWaveform => match &current_def {
+                Some(def) => waveforms(def, WAVEFORM_FREQ)
+                    .map(|w| Some(serde_json::to_value(w).unwrap()))
+                    .map_err(ErrorDetails::from),
+                None => Err(no_patch()),
+            },
+        };
+        if let Err(e) = &result {
+            println!("Request failed: {}", e.message);
+        }
+        if let Some(reply) = reply {
+            // the client may have gone away in the meantime
+            let _ = reply.send(result);
         }
     }
 }
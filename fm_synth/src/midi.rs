@@ -2,14 +2,55 @@ use std::io::{stdin, stdout, Write};
 
 use midir::{MidiInput, MidiInputPort};
 
-use crate::synth::SynthInputEvent;
+use fm::synth::SynthInputEvent;
 
-pub fn get_midi_input() -> Result<(MidiInput, MidiInputPort), String> {
+fn create_midi_input() -> Result<MidiInput, String> {
     let mut midi_in =
         MidiInput::new("FM Synth Input").map_err(|_| "Could not create midi input")?;
     midi_in.ignore(midir::Ignore::None);
+    Ok(midi_in)
+}
 
+/// Print the index and name of every MIDI input port.
+pub fn list_midi_ports() -> Result<(), String> {
+    let midi_in = create_midi_input()?;
+    for (i, p) in midi_in.ports().iter().enumerate() {
+        println!("{}: {}", i, midi_in.port_name(p).unwrap());
+    }
+    Ok(())
+}
+
+/// Open a MIDI input port. `selection` is either the index of the port, or a case insensitive
+/// part of its name. Without a selection, the port is guessed or chosen interactively.
+pub fn get_midi_input(selection: Option<&str>) -> Result<(MidiInput, MidiInputPort), String> {
+    let midi_in = create_midi_input()?;
     let mut in_ports = midi_in.ports();
+
+    if let Some(selection) = selection {
+        let index = match selection.parse::<usize>() {
+            Ok(index) if index < in_ports.len() => index,
+            Ok(_) => return Err(format!("no MIDI input port with index {}", selection)),
+            Err(_) => {
+                let selection = selection.to_lowercase();
+                in_ports
+                    .iter()
+                    .position(|p| {
+                        midi_in
+                            .port_name(p)
+                            .map(|name| name.to_lowercase().contains(&selection))
+                            .unwrap_or(false)
+                    })
+                    .ok_or_else(|| format!("no MIDI input port matching {}", selection))?
+            }
+        };
+        println!(
+            "Using input port: {}",
+            midi_in.port_name(&in_ports[index]).unwrap()
+        );
+        let port = in_ports.remove(index);
+        return Ok((midi_in, port));
+    }
+
     let in_port = match in_ports.len() {
         0 => return Err("no input port found".into()),
         1 => {
@@ -38,7 +79,7 @@ pub fn get_midi_input() -> Result<(MidiInput, MidiInputPort), String> {
                 .trim()
                 .parse::<usize>()
                 .map_err(|_| "couldnt parse port selection")?;
-            if index > in_ports.len() {
+            if index >= in_ports.len() {
                 return Err(String::from("Selected index out of range"));
             } else {
                 in_ports.remove(index)
@@ -51,17 +92,10 @@ pub fn get_midi_input() -> Result<(MidiInput, MidiInputPort), String> {
 
 pub fn parse_midi(bytes: &[u8]) -> Option<SynthInputEvent> {
     if (bytes[0] & 0xF0) == 0x90 {
-        Some(SynthInputEvent::KeyDown {
-            key: bytes[1],
-            freq: key_to_freq(bytes[1]),
-        })
+        Some(SynthInputEvent::key_down(bytes[1]))
     } else if (bytes[0] & 0xF0) == 0x80 {
         Some(SynthInputEvent::KeyUp { key: bytes[1] })
     } else {
         None
     }
 }
-
-fn key_to_freq(key: u8) -> f64 {
-    2.0_f64.powf((key as f64 - 69.0) / 12.0) * 440.0
-}
@@ -0,0 +1,187 @@
+use std::{fs, path::Path};
+
+use midly::{Format, MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+use fm::synth::{SynthInputEvent, TimedEvent, SAMPLE_RATE};
+
+/// Tempo assumed until the file sets one, in microseconds per beat (120 BPM).
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// Read the note events of a Standard MIDI File, timed in samples from the start of the file.
+/// Notes on all channels and tracks are merged.
+pub fn load_midi_file(path: &Path) -> Result<Vec<TimedEvent>, String> {
+    let bytes = fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    let smf =
+        Smf::parse(&bytes).map_err(|e| format!("could not parse {}: {}", path.display(), e))?;
+    Ok(midi_events(&smf))
+}
+
+/// Note events of a parsed MIDI file, see `load_midi_file`.
+fn midi_events(smf: &Smf) -> Vec<TimedEvent> {
+    // Flatten all tracks into (absolute tick, event) pairs. Sequential files play their tracks
+    // one after another, all other formats play them simultaneously.
+    let mut events = Vec::new();
+    let mut track_start = 0;
+    for track in &smf.tracks {
+        let mut tick = track_start;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            events.push((tick, event.kind));
+        }
+        if smf.header.format == Format::Sequential {
+            track_start = tick;
+        }
+    }
+    // stable sort keeps the order of events within a track
+    events.sort_by_key(|(tick, _)| *tick);
+
+    let mut timed = Vec::new();
+    let mut tempo = DEFAULT_TEMPO;
+    // time of the most recent tempo change, in ticks and seconds
+    let mut tempo_tick = 0;
+    let mut tempo_seconds = 0.0;
+    for (tick, kind) in events {
+        let seconds = tempo_seconds
+            + match smf.header.timing {
+                Timing::Metrical(ticks_per_beat) => {
+                    (tick - tempo_tick) as f64 * tempo as f64
+                        / 1_000_000.0
+                        / ticks_per_beat.as_int() as f64
+                }
+                Timing::Timecode(fps, subframes) => {
+                    (tick - tempo_tick) as f64 / (fps.as_f32() as f64 * subframes as f64)
+                }
+            };
+        let sample = (seconds * SAMPLE_RATE as f64).round() as u64;
+
+        let event = match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(t)) => {
+                tempo = t.as_int();
+                tempo_tick = tick;
+                tempo_seconds = seconds;
+                None
+            }
+            // by convention a note on with zero velocity is a note off
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { key, vel },
+                ..
+            } if vel.as_int() > 0 => Some(SynthInputEvent::key_down(key.as_int())),
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. },
+                ..
+            } => Some(SynthInputEvent::KeyUp { key: key.as_int() }),
+            _ => None,
+        };
+        if let Some(event) = event {
+            timed.push(TimedEvent { sample, event });
+        }
+    }
+
+    timed
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{Header, TrackEvent};
+
+    use super::*;
+
+    const TICKS_PER_BEAT: u16 = 480;
+    /// Samples per beat at the default tempo of 120 BPM
+    const BEAT: u64 = SAMPLE_RATE as u64 / 2;
+
+    fn event(delta: u32, kind: TrackEventKind<'static>) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind,
+        }
+    }
+
+    fn note_on(key: u8, vel: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Midi {
+            channel: 0.into(),
+            message: MidiMessage::NoteOn {
+                key: key.into(),
+                vel: vel.into(),
+            },
+        }
+    }
+
+    fn note_off(key: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Midi {
+            channel: 0.into(),
+            message: MidiMessage::NoteOff {
+                key: key.into(),
+                vel: 0.into(),
+            },
+        }
+    }
+
+    fn tempo(micros_per_beat: u32) -> TrackEventKind<'static> {
+        TrackEventKind::Meta(MetaMessage::Tempo(micros_per_beat.into()))
+    }
+
+    /// Events of a file with tracks played simultaneously, as (sample, key, whether key down)
+    fn events(tracks: Vec<Vec<TrackEvent<'static>>>) -> Vec<(u64, u8, bool)> {
+        let mut smf = Smf::new(Header::new(
+            Format::Parallel,
+            Timing::Metrical(TICKS_PER_BEAT.into()),
+        ));
+        smf.tracks = tracks;
+        midi_events(&smf)
+            .into_iter()
+            .map(|timed| match timed.event {
+                SynthInputEvent::KeyDown { key, .. } => (timed.sample, key, true),
+                SynthInputEvent::KeyUp { key } => (timed.sample, key, false),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ticks_to_samples() {
+        let beat = TICKS_PER_BEAT as u32;
+        let track = vec![
+            event(0, note_on(60, 100)),
+            event(beat, note_off(60)),
+            event(beat / 2, note_on(62, 100)),
+            event(beat / 2, note_off(62)),
+        ];
+        assert_eq!(
+            events(vec![track]),
+            vec![
+                (0, 60, true),
+                (BEAT, 60, false),
+                (BEAT * 3 / 2, 62, true),
+                (BEAT * 2, 62, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn tempo_change() {
+        let beat = TICKS_PER_BEAT as u32;
+        // the tempo doubles after the first beat, on a separate track from the notes
+        let tempo_track = vec![event(0, tempo(500_000)), event(beat, tempo(250_000))];
+        let notes = vec![
+            event(0, note_on(60, 100)),
+            event(beat, note_off(60)),
+            event(0, note_on(62, 100)),
+            event(beat, note_off(62)),
+        ];
+        assert_eq!(
+            events(vec![tempo_track, notes]),
+            vec![
+                (0, 60, true),
+                (BEAT, 60, false),
+                (BEAT, 62, true),
+                (BEAT * 3 / 2, 62, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_velocity_note_on_is_note_off() {
+        let track = vec![event(0, note_on(60, 100)), event(480, note_on(60, 0))];
+        assert_eq!(events(vec![track]), vec![(0, 60, true), (BEAT, 60, false)]);
+    }
+}
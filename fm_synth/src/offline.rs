@@ -0,0 +1,109 @@
+use std::{fs, path::Path};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+use serde::Deserialize;
+
+use fm::synth::{render, Meter, Patch, SynthInputEvent, TimedEvent, SAMPLE_RATE};
+
+use crate::{midi_file::load_midi_file, patch_file::load_patch};
+
+/// Seconds rendered after the last note is released, so release tails are not cut off.
+const TAIL_SECONDS: f64 = 1.0;
+
+/// A single note in a render script. Times are in seconds.
+#[derive(Deserialize, Debug)]
+struct ScriptNote {
+    key: u8,
+    start: f64,
+    duration: f64,
+}
+
+/// Convert seconds to a sample index.
+fn to_sample(seconds: f64) -> u64 {
+    (seconds * SAMPLE_RATE as f64).round() as u64
+}
+
+/// Read a script of notes, which is either a Standard MIDI File (`.mid`) or a JSON list of
+/// `{"key": .., "start": .., "duration": ..}` objects. Without a script, middle C is held for one
+/// second.
+fn load_script(path: Option<&Path>) -> Result<Vec<TimedEvent>, String> {
+    if let Some(path) = path.filter(|p| is_midi_file(p)) {
+        return load_midi_file(path);
+    }
+
+    let notes = match path {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+            serde_json::from_str(&text)
+                .map_err(|e| format!("could not parse {}: {}", path.display(), e))?
+        }
+        None => vec![ScriptNote {
+            key: 60,
+            start: 0.0,
+            duration: 1.0,
+        }],
+    };
+
+    let mut events = Vec::new();
+    for note in notes {
+        events.push(TimedEvent {
+            sample: to_sample(note.start),
+            event: SynthInputEvent::key_down(note.key),
+        });
+        events.push(TimedEvent {
+            sample: to_sample(note.start + note.duration),
+            event: SynthInputEvent::KeyUp { key: note.key },
+        });
+    }
+    // stable sort keeps a note's key up after its key down even for zero length notes
+    events.sort_by_key(|e| e.sample);
+    Ok(events)
+}
+
+fn is_midi_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("mid") | Some("midi")
+    )
+}
+
+/// Render the patch at `patch_path` with `num_voices` voices, playing the notes in `script_path`,
+/// to a stereo 32 bit float WAV file at `out_path`.
+pub fn render_to_wav(
+    patch_path: &Path,
+    out_path: &Path,
+    script_path: Option<&Path>,
+    num_voices: usize,
+) -> Result<(), String> {
+    let def = load_patch(patch_path)?;
+    let events = load_script(script_path)?;
+
+    let mut patch = Patch::new(&def, num_voices).map_err(|e| e.to_string())?;
+
+    let length = events.last().map(|e| e.sample).unwrap_or(0) + to_sample(TAIL_SECONDS);
+    let samples = render(&mut patch, &events, length);
+    let mut meter = Meter::new();
+    meter.add_block(&samples);
+
+    let spec = WavSpec {
+        channels: 2,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut writer = WavWriter::create(out_path, spec)
+        .map_err(|e| format!("could not create {}: {}", out_path.display(), e))?;
+    for sample in samples {
+        writer.write_sample(sample).map_err(|e| e.to_string())?;
+    }
+    writer.finalize().map_err(|e| e.to_string())?;
+
+    println!(
+        "Rendered {:.2}s to {}: {}",
+        length as f64 / SAMPLE_RATE as f64,
+        out_path.display(),
+        meter.take()
+    );
+    Ok(())
+}
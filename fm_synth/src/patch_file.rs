@@ -0,0 +1,76 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crossbeam_channel::Sender;
+
+use fm::synth::PatchDefinition;
+
+use crate::server::{ClientRequest, Request};
+
+/// How often a watched patch file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+/// Number of extra reads of a changed patch file that fails to parse.
+const PARSE_RETRIES: u32 = 4;
+
+/// Read and parse a patch JSON file.
+pub fn load_patch(path: &Path) -> Result<PatchDefinition, String> {
+    let text =
+        fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    serde_json::from_str(&text).map_err(|e| format!("could not parse {}: {}", path.display(), e))
+}
+
+/// Modification time and length of the file at `path`. Both are compared, as the modification
+/// time alone may be too coarse to tell two quick writes apart.
+fn version(path: &Path) -> Option<(SystemTime, u64)> {
+    fs::metadata(path)
+        .and_then(|m| Ok((m.modified()?, m.len())))
+        .ok()
+}
+
+/// Watch the patch file at `path` from a background thread, and send it over `sender` as an
+/// `UpdatePatch` request every time it changes. A change is only read once the file has stopped
+/// changing for one check, and a file that fails to parse is read again for the next
+/// `PARSE_RETRIES` checks, in case it was read while partially written.
+pub fn watch_patch(path: PathBuf, sender: Sender<Request>) {
+    thread::spawn(move || {
+        // version last read, and the version seen at the previous check
+        let mut loaded = version(&path);
+        let mut previous = loaded;
+        // reads of `loaded` left after it failed to parse
+        let mut retries = 0;
+        loop {
+            thread::sleep(WATCH_INTERVAL);
+            let current = version(&path);
+            let settled = current == previous;
+            previous = current;
+            if !settled || (current == loaded && retries == 0) {
+                continue;
+            }
+
+            match load_patch(&path) {
+                Ok(def) => {
+                    retries = 0;
+                    println!("Reloading {}", path.display());
+                    if sender.send(ClientRequest::UpdatePatch(def).into()).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    retries = if current == loaded {
+                        retries - 1
+                    } else {
+                        PARSE_RETRIES
+                    };
+                    if retries == 0 {
+                        println!("{}", e);
+                    }
+                }
+            }
+            loaded = current;
+        }
+    });
+}
@@ -1,50 +1,569 @@
 use std::{
-    net::{Ipv4Addr, TcpListener},
+    fmt,
+    io::ErrorKind,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
-use crossbeam_channel::Sender;
-use serde::Deserialize;
-use tungstenite::Message;
+use crossbeam_channel::{bounded, Receiver, Sender, TryRecvError, TrySendError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tungstenite::{handshake::HandshakeError, Error, Message, WebSocket};
 
-use crate::synth::PatchDefinition;
+use fm::synth::{Diagnostic, Levels, PatchDefinition, PatchError, PortUpdate};
+
+/// Version of the message format, sent with every message from the server. Bumped whenever a
+/// message changes in a way old clients would not understand.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How long a client thread waits for a request before sending out queued server messages.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// A client which has not sent anything for this long is pinged...
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+/// ...and dropped if it still has not answered after this long.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(20);
+/// Time allowed for a client to complete the websocket handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay between attempts to bind the server address.
+const BIND_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Server messages queued for a client which has not sent them out yet. A client falling
+/// further behind than this (several seconds of status reports) is disconnected.
+const CLIENT_QUEUE: usize = 64;
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
 pub enum ClientRequest {
     UpdatePatch(PatchDefinition),
+    /// Change the `mult`/`bias` of one port of the playing patch without reloading it
+    SetPort(PortUpdate),
+    /// Ask for the output of every node of the playing patch, see `fm::synth::waveforms`
     RequestWaveform,
 }
 
-/// Create new websocket server in background thread. Will send deserialized `ClientRequest`s
-/// over the channel using `sender`.
-pub fn start_websocket_server(sender: Sender<ClientRequest>) {
-    thread::spawn(move || {
-        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 8080)).unwrap();
-        for stream in server.incoming() {
-            let sender = sender.clone();
-            // New thread for each client
-            thread::spawn(move || {
-                let mut websocket = tungstenite::accept(stream.unwrap()).unwrap();
-
-                loop {
-                    if let Ok(Message::Text(text)) = websocket.read_message() {
-                        let req = serde_json::from_str(&text);
-                        if let Ok(req) = req {
-                            sender.send(req).unwrap();
-                            websocket
-                                .write_message(Message::Text(
-                                    "successfully received request".to_string(),
-                                ))
-                                .unwrap();
-                        } else {
-                            websocket
-                                .write_message(Message::Text("malformed request".to_string()))
-                                .unwrap();
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// The request was carried out
+    Ok,
+    /// The request was rejected, see `Response::error`
+    Error,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request is not valid JSON or not a known request
+    MalformedRequest,
+    /// The patch failed validation, see `Response::diagnostics`
+    InvalidPatch,
+    /// The request needs a patch to be playing
+    NoPatch,
+    /// `SetPort` named a node or port which does not exist
+    UnknownPort,
+}
+
+/// Why a request was rejected.
+#[derive(Serialize, Debug, Clone)]
+pub struct ErrorDetails {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorDetails {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorDetails {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<PatchError> for ErrorDetails {
+    fn from(e: PatchError) -> Self {
+        let code = match e {
+            PatchError::Cycle(_)
+            | PatchError::DelayOutOfRange { .. }
+            | PatchError::LinkOutOfRange { .. }
+            | PatchError::Invalid(_) => ErrorCode::InvalidPatch,
+            PatchError::UnknownPort { .. } => ErrorCode::UnknownPort,
+        };
+        ErrorDetails::new(code, e.to_string())
+    }
+}
+
+/// Result of a request carried out by the synth, with the data to send back to the client.
+pub type Reply = Result<Option<Value>, ErrorDetails>;
+
+/// A request passed on to the synth.
+pub struct Request {
+    pub request: ClientRequest,
+    /// Where to send the result, if a client is waiting for it.
+    pub reply: Option<Sender<Reply>>,
+}
+
+impl From<ClientRequest> for Request {
+    fn from(request: ClientRequest) -> Self {
+        Request {
+            request,
+            reply: None,
+        }
+    }
+}
+
+/// Reply to a `ClientRequest`.
+#[derive(Serialize, Debug)]
+pub struct Response {
+    /// Id of the request, if it had one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorDetails>,
+    /// Problems found when validating a patch. May contain warnings even if the request was
+    /// carried out.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+    /// Data requested by the client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Value>,
+}
+
+impl Response {
+    fn new(id: Option<u64>, diagnostics: Vec<Diagnostic>, reply: Reply) -> Self {
+        let (status, error, payload) = match reply {
+            Ok(payload) => (Status::Ok, None, payload),
+            Err(error) => (Status::Error, Some(error), None),
+        };
+        Response {
+            id,
+            status,
+            error,
+            diagnostics,
+            payload,
+        }
+    }
+}
+
+/// Every message sent by the server, tagged with `type`.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Response(Response),
+    /// Number of voices playing a note
+    VoiceActivity { active: usize, total: usize },
+    /// Fraction of the available time spent computing audio, above 1 the audio drops out
+    CpuLoad { load: f32 },
+    /// Peak and RMS output level of each channel since the last report
+    Levels(Levels),
+    /// Number of output samples above full scale since the last report, and the highest peak
+    Clipping { clipped: usize, peak: f32 },
+}
+
+impl ServerMessage {
+    fn to_text(&self) -> String {
+        #[derive(Serialize)]
+        struct Versioned<'a> {
+            version: u32,
+            #[serde(flatten)]
+            message: &'a ServerMessage,
+        }
+
+        serde_json::to_string(&Versioned {
+            version: PROTOCOL_VERSION,
+            message: self,
+        })
+        .unwrap()
+    }
+}
+
+fn malformed(id: Option<u64>, e: serde_json::Error) -> Response {
+    let error = ErrorDetails::new(ErrorCode::MalformedRequest, e.to_string());
+    Response::new(id, Vec::new(), Err(error))
+}
+
+/// Parse and check a request. Returns the request to pass on to the synth with its id and any
+/// warnings, or the response to send straight back if it is rejected.
+///
+/// A request can be wrapped as `{"id": 1, "request": ...}` to have the id copied into the
+/// response. Bare `ClientRequest`s get a response without an id.
+fn parse_request(text: &str) -> Result<(Option<u64>, ClientRequest, Vec<Diagnostic>), Response> {
+    let value: Value = serde_json::from_str(text).map_err(|e| malformed(None, e))?;
+    let (id, req) = match value.get("request") {
+        Some(req) => (value.get("id").and_then(Value::as_u64), req.clone()),
+        None => (None, value),
+    };
+    let req: ClientRequest = serde_json::from_value(req).map_err(|e| malformed(id, e))?;
+
+    let diagnostics = match &req {
+        ClientRequest::UpdatePatch(def) => def.validate(),
+        ClientRequest::SetPort(_) | ClientRequest::RequestWaveform => Vec::new(),
+    };
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        let error = ErrorDetails::new(ErrorCode::InvalidPatch, "patch failed validation");
+        return Err(Response::new(id, diagnostics, Err(error)));
+    }
+    Ok((id, req, diagnostics))
+}
+
+/// Message queue of a connected client, as seen by the `Broadcaster`.
+struct Client {
+    outgoing: Sender<String>,
+    /// Set when the client is dropped because its queue is full
+    fell_behind: Arc<AtomicBool>,
+}
+
+type Clients = Arc<Mutex<Vec<Client>>>;
+
+/// Sends server-initiated messages to every connected client.
+#[derive(Clone, Default)]
+pub struct Broadcaster {
+    clients: Clients,
+}
+
+impl Broadcaster {
+    pub fn send(&self, message: &ServerMessage) {
+        let text = message.to_text();
+        // clients which have disconnected or are not keeping up are dropped, which makes a
+        // connected client disconnect once it has sent out its queue
+        self.clients.lock().unwrap().retain(|client| {
+            match client.outgoing.try_send(text.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    client.fell_behind.store(true, Ordering::Relaxed);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+/// Why a client connection ended, other than the client closing it normally.
+#[derive(Debug)]
+enum Disconnect {
+    /// The connection was closed while writing to it
+    Closed,
+    /// The client stopped responding
+    TimedOut,
+    /// The synth stopped taking requests, ie. it is shutting down
+    SynthGone,
+    /// The client did not keep up with the server messages
+    FellBehind,
+    Error(String),
+}
+
+impl fmt::Display for Disconnect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Disconnect::Closed => write!(f, "connection closed"),
+            Disconnect::TimedOut => write!(f, "no response for {:?}", CLIENT_TIMEOUT),
+            Disconnect::SynthGone => write!(f, "synth stopped"),
+            Disconnect::FellBehind => write!(f, "more than {} messages behind", CLIENT_QUEUE),
+            Disconnect::Error(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<Error> for Disconnect {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::ConnectionClosed | Error::AlreadyClosed => Disconnect::Closed,
+            e => Disconnect::Error(e.to_string()),
+        }
+    }
+}
+
+fn is_timeout(e: &Error) -> bool {
+    matches!(e, Error::Io(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+}
+
+/// Answer a request from a client, waiting for the synth to carry it out.
+fn respond(text: &str, sender: &Sender<Request>) -> Result<Response, Disconnect> {
+    let (id, request, diagnostics) = match parse_request(text) {
+        Ok(parsed) => parsed,
+        Err(response) => return Ok(response),
+    };
+    let (reply_tx, reply_rx) = bounded(1);
+    sender
+        .send(Request {
+            request,
+            reply: Some(reply_tx),
+        })
+        .map_err(|_| Disconnect::SynthGone)?;
+    let reply = reply_rx.recv().map_err(|_| Disconnect::SynthGone)?;
+    Ok(Response::new(id, diagnostics, reply))
+}
+
+/// Talk to one client until the connection ends. Returns `Ok` if the client closed it.
+fn serve_client(
+    websocket: &mut WebSocket<TcpStream>,
+    sender: &Sender<Request>,
+    outgoing: &Receiver<String>,
+    fell_behind: &AtomicBool,
+) -> Result<(), Disconnect> {
+    // wake up regularly to send out broadcast messages and check the client is still there
+    websocket
+        .get_mut()
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .map_err(Error::Io)?;
+    let mut last_heard = Instant::now();
+    let mut pinged = false;
+
+    loop {
+        match websocket.read_message() {
+            Ok(message) => {
+                last_heard = Instant::now();
+                pinged = false;
+                match message {
+                    Message::Text(text) => {
+                        let response = respond(&text, sender)?;
+                        let text = ServerMessage::Response(response).to_text();
+                        websocket.write_message(Message::Text(text))?;
+                    }
+                    Message::Close(frame) => {
+                        if let Some(frame) = frame {
+                            println!("Client closing: {} {}", frame.code, frame.reason);
                         }
+                        // tungstenite replies to the close frame, keep reading until it is sent
                     }
+                    // pings are answered by tungstenite
+                    Message::Ping(_) | Message::Pong(_) => {}
+                    Message::Binary(_) => println!("Ignoring binary message from client"),
+                }
+            }
+            Err(Error::ConnectionClosed) => return Ok(()),
+            Err(e) if is_timeout(&e) => {
+                let silent = last_heard.elapsed();
+                if silent > CLIENT_TIMEOUT {
+                    return Err(Disconnect::TimedOut);
+                }
+                if silent > PING_INTERVAL && !pinged {
+                    websocket.write_message(Message::Ping(Vec::new()))?;
+                    pinged = true;
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        loop {
+            match outgoing.try_recv() {
+                Ok(text) => websocket.write_message(Message::Text(text))?,
+                Err(TryRecvError::Empty) => break,
+                // the broadcaster dropped this client, or was dropped itself at shutdown
+                Err(TryRecvError::Disconnected) if fell_behind.load(Ordering::Relaxed) => {
+                    return Err(Disconnect::FellBehind)
+                }
+                Err(TryRecvError::Disconnected) => return Err(Disconnect::SynthGone),
+            }
+        }
+        match websocket.write_pending() {
+            Err(e) if !is_timeout(&e) => return Err(e.into()),
+            _ => {}
+        }
+    }
+}
+
+/// Handshake with a new client and serve it from a new thread.
+fn spawn_client(stream: TcpStream, sender: Sender<Request>, clients: Clients) {
+    let peer = stream
+        .peer_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+    thread::spawn(move || {
+        // a client that stalls during the handshake must not keep the thread forever
+        if let Err(e) = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)) {
+            println!("Client {}: {}", peer, e);
+            return;
+        }
+        let mut websocket = match tungstenite::accept(stream) {
+            Ok(websocket) => websocket,
+            Err(HandshakeError::Interrupted(_)) => {
+                println!("Client {}: handshake timed out", peer);
+                return;
+            }
+            Err(HandshakeError::Failure(e)) => {
+                println!("Client {}: handshake failed: {}", peer, e);
+                return;
+            }
+        };
+        println!("Client {} connected", peer);
+
+        let (outgoing, outgoing_rx) = bounded(CLIENT_QUEUE);
+        let fell_behind = Arc::new(AtomicBool::new(false));
+        clients.lock().unwrap().push(Client {
+            outgoing,
+            fell_behind: fell_behind.clone(),
+        });
+        match serve_client(&mut websocket, &sender, &outgoing_rx, &fell_behind) {
+            Ok(()) | Err(Disconnect::Closed) => println!("Client {} disconnected", peer),
+            Err(e) => println!("Client {} dropped: {}", peer, e),
+        }
+        // dropping `outgoing_rx` unregisters the client from the broadcaster
+    });
+}
+
+/// Create new websocket server in background thread. Will send deserialized `ClientRequest`s
+/// over the channel using `sender`, and answer each with the `Reply` sent back by the synth.
+/// Returns a `Broadcaster` to push messages to all clients.
+///
+/// If `address` can not be bound, binding is retried until it succeeds. Failed connections
+/// are logged and do not affect other clients.
+pub fn start_websocket_server(address: SocketAddr, sender: Sender<Request>) -> Broadcaster {
+    let broadcaster = Broadcaster::default();
+    let clients = broadcaster.clients.clone();
+    thread::spawn(move || {
+        let server = loop {
+            match TcpListener::bind(address) {
+                Ok(server) => break server,
+                Err(e) => {
+                    println!(
+                        "Could not start websocket server on {}: {}, retrying",
+                        address, e
+                    );
+                    thread::sleep(BIND_RETRY_INTERVAL);
                 }
-            });
+            }
+        };
+        println!("Websocket server listening on {}", address);
+        for stream in server.incoming() {
+            match stream {
+                Ok(stream) => spawn_client(stream, sender.clone(), clients.clone()),
+                Err(e) => println!("Could not accept connection: {}", e),
+            }
         }
     });
+    broadcaster
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel::unbounded;
+    use serde_json::json;
+
+    use super::*;
+
+    fn to_json(message: ServerMessage) -> Value {
+        serde_json::from_str(&message.to_text()).unwrap()
+    }
+
+    #[test]
+    fn request_id_is_optional() {
+        let (id, req, _) = parse_request(r#"{"id": 7, "request": "request_waveform"}"#).unwrap();
+        assert_eq!(id, Some(7));
+        assert!(matches!(req, ClientRequest::RequestWaveform));
+
+        let (id, req, _) = parse_request(r#""request_waveform""#).unwrap();
+        assert_eq!(id, None);
+        assert!(matches!(req, ClientRequest::RequestWaveform));
+    }
+
+    #[test]
+    fn malformed_request_is_rejected() {
+        let response = parse_request(r#"{"id": 7, "request": "play_loud"}"#).unwrap_err();
+        let json = to_json(ServerMessage::Response(response));
+        assert_eq!(json["version"], PROTOCOL_VERSION);
+        assert_eq!(json["type"], "response");
+        assert_eq!(json["id"], 7);
+        assert_eq!(json["status"], "error");
+        assert_eq!(json["error"]["code"], "malformed_request");
+    }
+
+    #[test]
+    fn server_outlives_dropped_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+
+        let (sender, requests) = unbounded::<Request>();
+        start_websocket_server(address, sender);
+        thread::spawn(move || {
+            for request in requests {
+                let _ = request.reply.unwrap().send(Ok(None));
+            }
+        });
+
+        let url = format!("ws://{}", address);
+        let connect = || loop {
+            // the server binds in the background
+            if let Ok((socket, _)) = tungstenite::connect(&url) {
+                break socket;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        // one client goes away without closing, the other closes properly
+        drop(connect());
+        let mut socket = connect();
+        socket
+            .write_message(Message::Text(
+                r#"{"id": 1, "request": "request_waveform"}"#.to_string(),
+            ))
+            .unwrap();
+        let reply = loop {
+            if let Message::Text(text) = socket.read_message().unwrap() {
+                break serde_json::from_str::<Value>(&text).unwrap();
+            }
+        };
+        assert_eq!(reply["id"], 1);
+        assert_eq!(reply["status"], "ok");
+        socket.close(None).unwrap();
+    }
+
+    fn add_client(broadcaster: &Broadcaster) -> (Receiver<String>, Arc<AtomicBool>) {
+        let (outgoing, outgoing_rx) = bounded(CLIENT_QUEUE);
+        let fell_behind = Arc::new(AtomicBool::new(false));
+        broadcaster.clients.lock().unwrap().push(Client {
+            outgoing,
+            fell_behind: fell_behind.clone(),
+        });
+        (outgoing_rx, fell_behind)
+    }
+
+    #[test]
+    fn stalled_client_is_dropped() {
+        let broadcaster = Broadcaster::default();
+        let (outgoing_rx, fell_behind) = add_client(&broadcaster);
+
+        let message = ServerMessage::CpuLoad { load: 0.5 };
+        for _ in 0..CLIENT_QUEUE {
+            broadcaster.send(&message);
+        }
+        assert_eq!(broadcaster.clients.lock().unwrap().len(), 1);
+        // the queue is full, so the client is dropped instead of queueing more
+        broadcaster.send(&message);
+        assert!(broadcaster.clients.lock().unwrap().is_empty());
+        assert!(fell_behind.load(Ordering::Relaxed));
+
+        // the client still gets what was queued, then finds it was disconnected
+        assert_eq!(outgoing_rx.try_iter().count(), CLIENT_QUEUE);
+        assert_eq!(outgoing_rx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn dropped_broadcaster_is_not_falling_behind() {
+        let broadcaster = Broadcaster::default();
+        let (outgoing_rx, fell_behind) = add_client(&broadcaster);
+        broadcaster.send(&ServerMessage::CpuLoad { load: 0.5 });
+        drop(broadcaster);
+
+        assert_eq!(outgoing_rx.try_iter().count(), 1);
+        assert_eq!(outgoing_rx.try_recv(), Err(TryRecvError::Disconnected));
+        assert!(!fell_behind.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn server_messages_are_tagged() {
+        let json = to_json(ServerMessage::VoiceActivity {
+            active: 2,
+            total: 9,
+        });
+        assert_eq!(
+            json,
+            json!({ "version": PROTOCOL_VERSION, "type": "voice_activity", "active": 2, "total": 9 })
+        );
+    }
 }
@@ -1,5 +1,6 @@
 use crate::synth::dsp_node::DspNode;
 use crate::synth::port::{InPort, OutPort};
+use crate::synth::validate::DiagnosticKind;
 use crate::synth::SAMPLE_PERIOD;
 
 use super::voice::ProgramState;
@@ -9,12 +10,15 @@ node_definition! {
     Adsr(gate, a, d, s, r => out) {
         prev_gate: bool,
         state: AdsrState,
-        val: f64
+        val: f64,
+        // value when the gate was released, the release falls from this to 0 in `r` seconds
+        release_from: f64
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default, PartialEq)]
 enum AdsrState {
+    #[default]
     Idle,
     Attacking,
     Decaying,
@@ -22,12 +26,6 @@ enum AdsrState {
     Releasing,
 }
 
-impl Default for AdsrState {
-    fn default() -> Self {
-        Self::Idle
-    }
-}
-
 impl Adsr {
     // Returns whether a given gate value is considered 'on' and should trigger the ADSR.
     fn gate_on(gate: f64) -> bool {
@@ -36,13 +34,46 @@ impl Adsr {
 }
 
 impl DspNode for Adsr {
+    fn validate(&self) -> Vec<DiagnosticKind> {
+        let mut problems = Vec::new();
+        // Only constant inputs can be checked, linked inputs depend on the signal
+        let mut check = |name: &str, port: &InPort, valid: fn(f64) -> bool, reason: &str| {
+            if port.link().is_none() && !valid(port.bias()) {
+                problems.push(DiagnosticKind::InvalidConstant {
+                    port: name.to_string(),
+                    value: port.bias(),
+                    reason: reason.to_string(),
+                });
+            }
+        };
+        check("a", &self.a, |a| a > 0.0, "attack time must be positive");
+        check("d", &self.d, |d| d >= 0.0, "decay time must not be negative");
+        check("s", &self.s, |s| s >= 0.0, "sustain level must not be negative");
+        check("r", &self.r, |r| r >= 0.0, "release time must not be negative");
+        problems
+    }
+
+    fn is_idle(&self) -> Option<bool> {
+        Some(self.state == AdsrState::Idle)
+    }
+
     fn next_sample(&mut self, state: &mut ProgramState) {
+        if state.bypass_envelopes {
+            self.out.write(1.0, state);
+            return;
+        }
+
         self.resolve_inputs(state);
         let gate = Self::gate_on(self.resolved.gate);
 
         if !self.prev_gate && gate {
             self.val = 0.0;
             self.state = AdsrState::Attacking;
+        } else if self.prev_gate && !gate && self.state != AdsrState::Idle {
+            // the gate may be released before the sustain is reached, so release from wherever
+            // the envelope is
+            self.release_from = self.val;
+            self.state = AdsrState::Releasing;
         }
 
         match self.state {
@@ -61,14 +92,10 @@ impl DspNode for Adsr {
                     self.state = AdsrState::Sustaining;
                 }
             }
-            AdsrState::Sustaining => {
-                if self.prev_gate && !gate {
-                    self.state = AdsrState::Releasing;
-                }
-            }
+            AdsrState::Sustaining => {}
             AdsrState::Releasing => {
-                self.val -= self.resolved.s * (SAMPLE_PERIOD / self.resolved.r);
-                if self.resolved.s == 0.0 || self.val <= 0.0 {
+                self.val -= self.release_from * (SAMPLE_PERIOD / self.resolved.r);
+                if self.release_from == 0.0 || self.val <= 0.0 {
                     // TODO notify note finished?
                     self.val = 0.0;
                     self.state = AdsrState::Idle;
@@ -81,3 +108,126 @@ impl DspNode for Adsr {
         self.out.write(self.val, state);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::synth::test_util::{constant, from_json, linked, output, state};
+
+    const GATE: usize = 0;
+    const OUT: usize = 1;
+
+    /// ADSR with 10ms attack, decay and release (441 samples each) and a sustain of 0.5
+    fn adsr() -> Adsr {
+        from_json(json!({
+            "inputs": {
+                "gate": linked(GATE),
+                "a": constant(0.01),
+                "d": constant(0.01),
+                "s": constant(0.5),
+                "r": constant(0.01),
+            },
+            "outputs": { "out": output(OUT) }
+        }))
+    }
+
+    /// Run the ADSR for `n` samples, returning its output at each sample
+    fn run(adsr: &mut Adsr, state: &mut ProgramState, n: usize) -> Vec<f64> {
+        (0..n)
+            .map(|_| {
+                adsr.next_sample(state);
+                state.links[OUT]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn idle_without_gate() {
+        let mut adsr = adsr();
+        let mut state = state(2);
+        assert!(run(&mut adsr, &mut state, 100).iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn stages() {
+        let mut adsr = adsr();
+        let mut state = state(2);
+        state.links[GATE] = 1.0;
+
+        // attack rises linearly to 1
+        let attack = run(&mut adsr, &mut state, 441);
+        assert!(attack.windows(2).all(|w| w[1] > w[0]));
+        assert!((attack[220] - 0.5).abs() < 0.01);
+        assert!(attack[440] > 0.99);
+
+        // decay falls to the sustain level
+        let decay = run(&mut adsr, &mut state, 443);
+        assert!(decay[..400].windows(2).all(|w| w[1] < w[0]));
+        assert_eq!(*decay.last().unwrap(), 0.5);
+
+        // sustain holds while the gate is on
+        let sustain = run(&mut adsr, &mut state, 1000);
+        assert!(sustain.iter().all(|&x| x == 0.5));
+
+        // release falls to zero and stays there
+        state.links[GATE] = 0.0;
+        let release = run(&mut adsr, &mut state, 443);
+        assert!(release[..400].windows(2).all(|w| w[1] < w[0]));
+        assert!((release[220] - 0.25).abs() < 0.01);
+        assert_eq!(*release.last().unwrap(), 0.0);
+        assert!(run(&mut adsr, &mut state, 100).iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn release_during_attack() {
+        let mut adsr = adsr();
+        let mut state = state(2);
+        state.links[GATE] = 1.0;
+        let attack = run(&mut adsr, &mut state, 200);
+        let peak = *attack.last().unwrap();
+        assert!(peak < 0.5);
+
+        // the release starts from the current level rather than the sustain level
+        state.links[GATE] = 0.0;
+        let release = run(&mut adsr, &mut state, 443);
+        assert!(release[0] < peak);
+        assert!(release[..400].windows(2).all(|w| w[1] < w[0]));
+        assert_eq!(*release.last().unwrap(), 0.0);
+        assert_eq!(adsr.is_idle(), Some(true));
+    }
+
+    #[test]
+    fn retrigger_restarts_attack() {
+        let mut adsr = adsr();
+        let mut state = state(2);
+        state.links[GATE] = 1.0;
+        run(&mut adsr, &mut state, 2000);
+        state.links[GATE] = 0.0;
+        run(&mut adsr, &mut state, 100);
+        state.links[GATE] = 1.0;
+        let attack = run(&mut adsr, &mut state, 1);
+        assert!(attack[0] < 0.01);
+    }
+
+    #[test]
+    fn validate_rejects_zero_attack() {
+        let adsr: Adsr = from_json(json!({
+            "inputs": {
+                "gate": linked(GATE),
+                "a": constant(0.0),
+                "d": constant(0.01),
+                "s": constant(0.5),
+                "r": constant(0.01),
+            },
+            "outputs": { "out": output(OUT) }
+        }));
+        let problems = adsr.validate();
+        assert_eq!(problems.len(), 1);
+        assert!(matches!(
+            &problems[0],
+            DiagnosticKind::InvalidConstant { port, .. } if port == "a"
+        ));
+    }
+}
@@ -0,0 +1,184 @@
+use serde::Deserialize;
+
+/// How a voice is chosen for a new note. Idle voices are always used first, then the voice
+/// whose note was released longest ago, so new notes cut off as little of a release tail as
+/// possible. The policies differ in what happens when every voice is holding a note.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VoiceAllocation {
+    /// Steal the voice of the note held longest
+    #[default]
+    StealOldest,
+    /// Steal the voice of the note pressed most recently
+    StealNewest,
+    /// Ignore the new note
+    NoSteal,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum VoiceState {
+    /// Silent, either because it has not played a note yet or because its last note finished
+    Idle,
+    /// Holding `key`, which was pressed at time `since`
+    Held { key: u8, freq: f64, since: u64 },
+    /// The note was released at time `since`, the voice may still be sounding its release
+    Released { since: u64 },
+}
+
+/// Voice to play a new note on, see `VoiceAllocator::note_on`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    pub voice: usize,
+    /// Whether the voice was holding another note, which must be cut off
+    pub stolen: bool,
+}
+
+/// Keeps track of which note each voice of a patch is playing, and picks voices for new notes.
+#[derive(Clone, Debug)]
+pub struct VoiceAllocator {
+    policy: VoiceAllocation,
+    voices: Vec<VoiceState>,
+    // counts note on and off events, to order them
+    clock: u64,
+}
+
+impl VoiceAllocator {
+    pub fn new(policy: VoiceAllocation, num_voices: usize) -> Self {
+        VoiceAllocator {
+            policy,
+            voices: vec![VoiceState::Idle; num_voices],
+            clock: 0,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Choose a voice for `key`, or `None` if the note should be dropped.
+    pub fn note_on(&mut self, key: u8, freq: f64) -> Option<Allocation> {
+        let idle = self.voices.iter().position(|v| *v == VoiceState::Idle);
+        let released = || {
+            self.voices
+                .iter()
+                .enumerate()
+                .filter_map(|(i, v)| match v {
+                    VoiceState::Released { since } => Some((i, *since)),
+                    _ => None,
+                })
+                .min_by_key(|&(_, since)| since)
+                .map(|(i, _)| i)
+        };
+        let held = self.voices.iter().enumerate().filter_map(|(i, v)| match v {
+            VoiceState::Held { since, .. } => Some((i, *since)),
+            _ => None,
+        });
+        let stolen = match self.policy {
+            VoiceAllocation::StealOldest => held.min_by_key(|&(_, since)| since),
+            VoiceAllocation::StealNewest => held.max_by_key(|&(_, since)| since),
+            VoiceAllocation::NoSteal => None,
+        }
+        .map(|(i, _)| i);
+
+        let allocation = match idle.or_else(released) {
+            Some(voice) => Allocation {
+                voice,
+                stolen: false,
+            },
+            None => Allocation {
+                voice: stolen?,
+                stolen: true,
+            },
+        };
+        let since = self.tick();
+        self.voices[allocation.voice] = VoiceState::Held { key, freq, since };
+        Some(allocation)
+    }
+
+    /// Release every voice holding `key`, returning their indices.
+    pub fn note_off(&mut self, key: u8) -> Vec<usize> {
+        let since = self.tick();
+        let mut released = Vec::new();
+        for (i, voice) in self.voices.iter_mut().enumerate() {
+            if matches!(voice, VoiceState::Held { key: k, .. } if *k == key) {
+                *voice = VoiceState::Released { since };
+                released.push(i);
+            }
+        }
+        released
+    }
+
+    /// Whether `voice` had its note released and has not been marked idle since.
+    pub fn is_released(&self, voice: usize) -> bool {
+        matches!(self.voices[voice], VoiceState::Released { .. })
+    }
+
+    /// Mark a released voice as idle once it has gone silent, so it is preferred for new notes.
+    pub fn voice_finished(&mut self, voice: usize) {
+        if self.is_released(voice) {
+            self.voices[voice] = VoiceState::Idle;
+        }
+    }
+
+    /// Key and frequency of every held note, oldest first.
+    pub fn held_notes(&self) -> Vec<(u8, f64)> {
+        let mut held: Vec<_> = self
+            .voices
+            .iter()
+            .filter_map(|v| match *v {
+                VoiceState::Held { key, freq, since } => Some((since, key, freq)),
+                _ => None,
+            })
+            .collect();
+        held.sort_by_key(|&(since, _, _)| since);
+        held.into_iter().map(|(_, key, freq)| (key, freq)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocator(policy: VoiceAllocation, num_voices: usize) -> VoiceAllocator {
+        VoiceAllocator::new(policy, num_voices)
+    }
+
+    fn voice(allocation: Option<Allocation>) -> usize {
+        allocation.unwrap().voice
+    }
+
+    #[test]
+    fn reuses_oldest_released_voice() {
+        let mut voices = allocator(VoiceAllocation::StealOldest, 3);
+        assert_eq!(voice(voices.note_on(60, 0.0)), 0);
+        assert_eq!(voice(voices.note_on(62, 0.0)), 1);
+        assert_eq!(voices.note_off(62), vec![1]);
+        assert_eq!(voices.note_off(60), vec![0]);
+        // the idle voice comes first, then the voice released longest ago
+        assert_eq!(voice(voices.note_on(64, 0.0)), 2);
+        assert_eq!(voice(voices.note_on(65, 0.0)), 1);
+        assert_eq!(voice(voices.note_on(67, 0.0)), 0);
+    }
+
+    #[test]
+    fn steals_held_voices_by_policy() {
+        let mut oldest = allocator(VoiceAllocation::StealOldest, 2);
+        let mut newest = allocator(VoiceAllocation::StealNewest, 2);
+        let mut none = allocator(VoiceAllocation::NoSteal, 2);
+        for voices in [&mut oldest, &mut newest, &mut none] {
+            voices.note_on(60, 0.0);
+            voices.note_on(62, 0.0);
+        }
+
+        let stolen = Allocation {
+            voice: 0,
+            stolen: true,
+        };
+        assert_eq!(oldest.note_on(64, 0.0), Some(stolen));
+        assert_eq!(oldest.held_notes(), vec![(62, 0.0), (64, 0.0)]);
+        assert_eq!(newest.note_on(64, 0.0), Some(Allocation { voice: 1, ..stolen }));
+        assert_eq!(none.note_on(64, 0.0), None);
+        assert_eq!(none.held_notes(), vec![(60, 0.0), (62, 0.0)]);
+    }
+}
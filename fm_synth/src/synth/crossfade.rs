@@ -0,0 +1,90 @@
+use crate::synth::dsp_node::DspNode;
+use crate::synth::port::{InPort, OutPort};
+
+use super::{equal_power_pan, voice::ProgramState};
+
+/// How the gains of the two inputs of a `Crossfade` follow its position.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Curve {
+    /// Gains add up to 1, best for correlated inputs (eg. two operators at the same frequency)
+    #[default]
+    Linear,
+    /// Squared gains add up to 1, so unrelated inputs keep their loudness in the middle
+    EqualPower,
+}
+
+node_definition! {
+    #[derive(Default, Clone, Debug)]
+    Crossfade(a, b, position => out) params { curve: Curve }
+}
+
+impl Crossfade {
+    /// Gains of `a` and `b` at `position`, from 0 (only `a`) to 1 (only `b`).
+    fn gains(&self, position: f64) -> (f64, f64) {
+        let position = position.clamp(0.0, 1.0);
+        match self.curve {
+            Curve::Linear => (1.0 - position, position),
+            Curve::EqualPower => equal_power_pan(position * 2.0 - 1.0),
+        }
+    }
+}
+
+impl DspNode for Crossfade {
+    fn next_sample(&mut self, state: &mut ProgramState) {
+        self.resolve_inputs(state);
+        let (gain_a, gain_b) = self.gains(self.resolved.position);
+        let out = self.resolved.a * gain_a + self.resolved.b * gain_b;
+        self.out.write(out, state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::synth::test_util::{constant, from_json, linked, output, state};
+
+    fn crossfade(params: Value, position: f64) -> f64 {
+        let mut crossfade: Crossfade = from_json(json!({
+            "inputs": { "a": linked(0), "b": linked(1), "position": constant(position) },
+            "outputs": { "out": output(2) },
+            "params": params
+        }));
+        let mut state = state(3);
+        state.links[0] = 1.0;
+        state.links[1] = -0.5;
+        crossfade.next_sample(&mut state);
+        state.links[2]
+    }
+
+    #[test]
+    fn linear() {
+        // linear is the default curve
+        assert_eq!(crossfade(json!({}), 0.0), 1.0);
+        assert_eq!(crossfade(json!({ "curve": "linear" }), 0.5), 0.25);
+        assert_eq!(crossfade(json!({}), 1.0), -0.5);
+        // positions outside 0 to 1 are clamped
+        assert_eq!(crossfade(json!({}), -2.0), 1.0);
+        assert_eq!(crossfade(json!({}), 4.0), -0.5);
+    }
+
+    #[test]
+    fn equal_power() {
+        let params = json!({ "curve": "equal_power" });
+        assert!((crossfade(params.clone(), 0.0) - 1.0).abs() < 1e-12);
+        assert!((crossfade(params.clone(), 1.0) + 0.5).abs() < 1e-12);
+        let middle = 0.5 * std::f64::consts::FRAC_1_SQRT_2;
+        assert!((crossfade(params, 0.5) - middle).abs() < 1e-12);
+    }
+
+    #[test]
+    fn params_are_optional() {
+        let crossfade: Crossfade = from_json(json!({
+            "inputs": { "a": linked(0), "b": linked(1), "position": constant(0.5) },
+            "outputs": { "out": output(2) }
+        }));
+        assert_eq!(crossfade.curve, Curve::Linear);
+    }
+}
@@ -1,16 +1,37 @@
+use crate::synth::port::{InPort, OutPort};
+use crate::synth::validate::DiagnosticKind;
 use crate::synth::voice::ProgramState;
 
-pub trait DspNode {
+pub trait DspNode: NodePorts {
     fn next_sample(&mut self, state: &mut ProgramState);
+
+    /// Node specific checks of the port values, reported as errors by
+    /// `PatchDefinition::validate`.
+    fn validate(&self) -> Vec<DiagnosticKind> {
+        Vec::new()
+    }
+
+    /// For envelopes, whether the envelope has finished and rests at zero. `None` for nodes
+    /// which are not envelopes. Used to find out when a released voice has gone silent.
+    fn is_idle(&self) -> Option<bool> {
+        None
+    }
+}
+
+/// Access to the ports of a node by name. Implemented automatically by `node_definition!`.
+pub trait NodePorts {
+    fn inputs(&self) -> Vec<(String, &InPort)>;
+    fn outputs(&self) -> Vec<(String, &OutPort)>;
+    fn input_mut(&mut self, name: &str) -> Option<&mut InPort>;
 }
 
 /// Define a node type with inputs, outputs, and fields. This automatically implements Deserialize.
 /// Syntax:
-/// ```
+/// ```ignore
 /// node_definition! {
 ///     #[OptionalAttribute1]
 ///     #[OptionalAttribute2]
-///     NodeName(input1, input2 => output1, output2) {
+///     NodeName(input1, #[default] input2 => output1, output2) params { param1: Type } {
 ///         #[OptionalAttributeOrDocComment]
 ///         pub additionalField1: Type,
 ///         #[OptionalAttributeOrDocComment]
@@ -18,11 +39,22 @@ pub trait DspNode {
 ///     }
 /// }
 /// ```
-/// The braced block with additional fields is optional.
+/// Inputs are required, unless marked `#[default]`. A missing default input is the same as an
+/// unconnected input with a value of 0, so inputs can be added to a node without breaking saved
+/// patches. The `params` and braced blocks are optional. Settings which are not signals (such as
+/// the shape of a curve) are declared as params. They are read from the `params` object of the
+/// node in the patch, and are left at their default when missing, so each type must implement
+/// `Default`.
 macro_rules! node_definition {
+    // the only attribute allowed on an input
+    (@input_attribute default) => {};
     (
         $(#[$attribute:meta $($attributeArgs:tt)* ])*
-        $structName:ident( $($inputName:ident),* => $($outputName:ident),*) $( {
+        $structName:ident(
+            $( $(#[$inputAttribute:ident])? $inputName:ident ),* => $($outputName:ident),*
+        )
+        $( params { $( $paramName:ident: $paramType:ty ),+ $(,)? } )?
+        $( {
             $(
 
             $( #[ $fieldAttribute:meta $($fieldAttributeArgs:tt)* ] )*
@@ -33,6 +65,8 @@ macro_rules! node_definition {
             $(,)?
         } )?
     ) => {
+        $( $( node_definition!(@input_attribute $inputAttribute); )? )*
+
         use serde::{Deserialize, de::Deserializer};
 
         // ResolvedInputs is a struct with values corresponding to all InputPorts
@@ -46,6 +80,7 @@ macro_rules! node_definition {
             resolved: ResolvedInputs,
             $( $inputName: InPort, )*
             $( $outputName: OutPort, )*
+            $($( $paramName: $paramType, )+)?
             $($(
                 $( #[ $fieldAttribute $($fieldAttributeArgs)* ] )*
                 $fieldVisibility $fieldName: $fieldType,
@@ -62,11 +97,28 @@ macro_rules! node_definition {
             }
         }
 
+        impl crate::synth::dsp_node::NodePorts for $structName {
+            fn inputs(&self) -> Vec<(String, &InPort)> {
+                vec![ $( (stringify!($inputName).to_string(), &self.$inputName), )* ]
+            }
+
+            fn outputs(&self) -> Vec<(String, &OutPort)> {
+                vec![ $( (stringify!($outputName).to_string(), &self.$outputName), )* ]
+            }
+
+            fn input_mut(&mut self, name: &str) -> Option<&mut InPort> {
+                match name {
+                    $( stringify!($inputName) => Some(&mut self.$inputName), )*
+                    _ => None,
+                }
+            }
+        }
+
         impl<'de> Deserialize<'de> for $structName {
             fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
                 #[derive(Deserialize)]
                 struct Inputs {
-                    $( $inputName: InPort, )*
+                    $( $(#[serde($inputAttribute)])? $inputName: InPort, )*
                 }
 
                 #[derive(Deserialize)]
@@ -74,16 +126,25 @@ macro_rules! node_definition {
                     $( $outputName: OutPort, )*
                 }
 
+                #[derive(Deserialize, Default)]
+                struct Params {
+                    $($( #[serde(default)] $paramName: $paramType, )+)?
+                }
+
                 #[derive(Deserialize)]
                 struct UnFlattened {
                     inputs: Inputs,
-                    outputs: Outputs
+                    outputs: Outputs,
+                    #[serde(default)]
+                    params: Params,
                 }
 
-                let unflattened = UnFlattened::deserialize(deserializer)?;
+                let UnFlattened { inputs, outputs, params: _params } =
+                    UnFlattened::deserialize(deserializer)?;
                 Ok($structName {
-                    $( $inputName: unflattened.inputs.$inputName, )*
-                    $( $outputName: unflattened.outputs.$outputName, )*
+                    $( $inputName: inputs.$inputName, )*
+                    $( $outputName: outputs.$outputName, )*
+                    $($( $paramName: _params.$paramName, )+)?
                     .. Default::default()
                 })
             }
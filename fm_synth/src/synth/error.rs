@@ -0,0 +1,63 @@
+use std::{error::Error, fmt::Display};
+
+use super::{
+    serialized::{MAX_DELAY, MAX_LINKS},
+    validate::Diagnostic,
+};
+
+/// Reasons a `PatchDefinition` can not be loaded into a `Patch`, or a change can not be applied
+/// to a patch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchError {
+    /// The links between these nodes (indices into `PatchDefinition::nodes`) form a cycle, so
+    /// there is no order in which they can be run.
+    Cycle(Vec<usize>),
+    /// An input of node `node` reads a link delayed by more than `MAX_DELAY` samples.
+    DelayOutOfRange { node: usize, delay: usize },
+    /// A link index is not below `MAX_LINKS`. `node` is `None` if the link is referenced by
+    /// the patch IO rather than a node.
+    LinkOutOfRange { node: Option<usize>, link: usize },
+    /// Validation found errors in the patch. Only diagnostics with `Severity::Error` are kept.
+    Invalid(Vec<Diagnostic>),
+    /// The patch has no node with this index, or the node has no input port with this name.
+    UnknownPort { node: usize, port: String },
+}
+
+impl Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatchError::Cycle(nodes) => {
+                write!(
+                    f,
+                    "patch contains a cycle between nodes {:?}, give one of the links a delay to \
+                     make it a feedback loop",
+                    nodes
+                )
+            }
+            PatchError::DelayOutOfRange { node, delay } => write!(
+                f,
+                "node {} is delayed by {} samples, delays must be at most {}",
+                node, delay, MAX_DELAY
+            ),
+            PatchError::LinkOutOfRange { node, link } => {
+                match node {
+                    Some(node) => write!(f, "node {} uses link {}", node, link)?,
+                    None => write!(f, "patch IO uses link {}", link)?,
+                }
+                write!(f, ", link indices must be below {}", MAX_LINKS)
+            }
+            PatchError::Invalid(diagnostics) => {
+                write!(f, "invalid patch")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n  {}", diagnostic)?;
+                }
+                Ok(())
+            }
+            PatchError::UnknownPort { node, port } => {
+                write!(f, "node {} has no input port {}", node, port)
+            }
+        }
+    }
+}
+
+impl Error for PatchError {}
@@ -0,0 +1,137 @@
+use std::collections::{BTreeSet, HashMap};
+
+use super::{error::PatchError, serialized::PatchDefinition};
+
+/// Compute the order in which the nodes of `def` must run so that every node runs after all
+/// nodes that write to its inputs. Returned values are indices into `def.nodes`. Nodes which do
+/// not depend on each other keep their relative order from the definition.
+///
+/// Inputs with a `delay` only read values from previous samples, so they do not create a
+/// dependency. This is what allows feedback loops.
+pub fn execution_order(def: &PatchDefinition) -> Result<Vec<usize>, PatchError> {
+    // map each link to the nodes writing to it
+    let mut writers: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (i, node) in def.nodes.iter().enumerate() {
+        for (_, port) in node.as_node().outputs() {
+            if let Some(link) = port.link() {
+                writers.entry(link).or_default().push(i);
+            }
+        }
+    }
+
+    // dependents[i] lists the nodes reading an output of node i
+    let mut dependents = vec![Vec::new(); def.nodes.len()];
+    let mut num_dependencies = vec![0; def.nodes.len()];
+    for (i, node) in def.nodes.iter().enumerate() {
+        let mut dependencies = BTreeSet::new();
+        for (_, port) in node.as_node().inputs() {
+            if port.delay().is_some() {
+                continue;
+            }
+            if let Some(link_writers) = port.link().and_then(|link| writers.get(&link)) {
+                dependencies.extend(link_writers.iter().copied());
+            }
+        }
+        num_dependencies[i] = dependencies.len();
+        for dependency in dependencies {
+            dependents[dependency].push(i);
+        }
+    }
+
+    // Kahn's algorithm, always taking the lowest ready index so the result is deterministic
+    let mut ready: BTreeSet<usize> = (0..def.nodes.len())
+        .filter(|&i| num_dependencies[i] == 0)
+        .collect();
+    let mut order = Vec::with_capacity(def.nodes.len());
+    while let Some(i) = ready.iter().next().copied() {
+        ready.remove(&i);
+        order.push(i);
+        for &dependent in &dependents[i] {
+            num_dependencies[dependent] -= 1;
+            if num_dependencies[dependent] == 0 {
+                ready.insert(dependent);
+            }
+        }
+    }
+
+    if order.len() == def.nodes.len() {
+        Ok(order)
+    } else {
+        Err(PatchError::Cycle(cyclic_nodes(&dependents, &num_dependencies)))
+    }
+}
+
+/// Given the nodes left unscheduled by the topological sort, strip those which are merely
+/// downstream of a cycle so that only the nodes forming the cycle(s) remain.
+fn cyclic_nodes(dependents: &[Vec<usize>], num_dependencies: &[usize]) -> Vec<usize> {
+    let mut remaining: Vec<bool> = num_dependencies.iter().map(|&n| n > 0).collect();
+    loop {
+        let downstream: Vec<usize> = (0..remaining.len())
+            .filter(|&i| remaining[i] && !dependents[i].iter().any(|&d| remaining[d]))
+            .collect();
+        if downstream.is_empty() {
+            break;
+        }
+        for i in downstream {
+            remaining[i] = false;
+        }
+    }
+    (0..remaining.len()).filter(|&i| remaining[i]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::synth::test_util::{constant, from_json, linked, output};
+
+    /// Oscillator with its phase modulated by `phase` and writing to `out`
+    fn osc(phase: Value, out: usize) -> Value {
+        json!({
+            "type": "sinosc",
+            "inputs": {
+                "freq": linked(0),
+                "phase": phase,
+                "vol": constant(1.0),
+                "feedback": constant(0.0),
+            },
+            "outputs": { "out": output(out) }
+        })
+    }
+
+    fn patch(nodes: Vec<Value>) -> PatchDefinition {
+        from_json(json!({ "nodes": nodes, "io": { "freq": 0, "lchan": 1 } }))
+    }
+
+    #[test]
+    fn modulator_runs_before_carrier() {
+        let def = patch(vec![osc(linked(2), 1), osc(constant(0.0), 2)]);
+        assert_eq!(execution_order(&def), Ok(vec![1, 0]));
+    }
+
+    #[test]
+    fn independent_nodes_keep_definition_order() {
+        let def = patch(vec![osc(constant(0.0), 1), osc(constant(0.0), 2)]);
+        assert_eq!(execution_order(&def), Ok(vec![0, 1]));
+    }
+
+    #[test]
+    fn cycle_is_reported() {
+        // 0 and 1 modulate each other, 2 is only downstream of the cycle
+        let def = patch(vec![
+            osc(linked(2), 1),
+            osc(linked(1), 2),
+            osc(linked(2), 3),
+        ]);
+        assert_eq!(execution_order(&def), Err(PatchError::Cycle(vec![0, 1])));
+    }
+
+    #[test]
+    fn delayed_link_breaks_cycle() {
+        let mut delayed = linked(1);
+        delayed["delay"] = json!(1);
+        let def = patch(vec![osc(linked(2), 1), osc(delayed, 2)]);
+        assert_eq!(execution_order(&def), Ok(vec![1, 0]));
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+
+use serde::Serialize;
+
+/// Level of one channel over a stretch of audio.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChannelLevel {
+    /// Largest absolute sample.
+    pub peak: f32,
+    pub rms: f32,
+    /// Number of samples above full scale (absolute value over 1).
+    pub clipped: usize,
+}
+
+/// Stereo output levels, see `Meter`.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Levels {
+    pub left: ChannelLevel,
+    pub right: ChannelLevel,
+}
+
+impl Levels {
+    pub fn clipped(&self) -> usize {
+        self.left.clipped + self.right.clipped
+    }
+}
+
+/// Format a level in dB relative to full scale.
+fn dbfs(level: f32) -> String {
+    if level > 0.0 {
+        format!("{:.1}", 20.0 * level.log10())
+    } else {
+        "-inf".to_string()
+    }
+}
+
+impl fmt::Display for Levels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "peak {}/{} dBFS, rms {}/{} dBFS, {} samples clipped",
+            dbfs(self.left.peak),
+            dbfs(self.right.peak),
+            dbfs(self.left.rms),
+            dbfs(self.right.rms),
+            self.clipped()
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct ChannelMeter {
+    peak: f32,
+    sum_squares: f64,
+    clipped: usize,
+}
+
+impl ChannelMeter {
+    fn add(&mut self, sample: f32) {
+        self.peak = self.peak.max(sample.abs());
+        self.sum_squares += sample as f64 * sample as f64;
+        if sample.abs() > 1.0 {
+            self.clipped += 1;
+        }
+    }
+
+    fn level(&self, frames: usize) -> ChannelLevel {
+        ChannelLevel {
+            peak: self.peak,
+            rms: if frames == 0 {
+                0.0
+            } else {
+                (self.sum_squares / frames as f64).sqrt() as f32
+            },
+            clipped: self.clipped,
+        }
+    }
+}
+
+/// Measures the peak, RMS and clipping of stereo audio. Frames are added with `add_frame` or
+/// `add_block` until the levels are read and reset with `take`.
+#[derive(Debug, Default)]
+pub struct Meter {
+    left: ChannelMeter,
+    right: ChannelMeter,
+    frames: usize,
+}
+
+impl Meter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_frame(&mut self, left: f32, right: f32) {
+        self.left.add(left);
+        self.right.add(right);
+        self.frames += 1;
+    }
+
+    /// Add interleaved left/right samples.
+    pub fn add_block(&mut self, block: &[f32]) {
+        for frame in block.chunks_exact(2) {
+            self.add_frame(frame[0], frame[1]);
+        }
+    }
+
+    /// Levels of everything added since the last call.
+    pub fn take(&mut self) -> Levels {
+        let levels = Levels {
+            left: self.left.level(self.frames),
+            right: self.right.level(self.frames),
+        };
+        *self = Self::default();
+        levels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_each_channel() {
+        let mut meter = Meter::new();
+        // left is a full scale square wave, right clips twice
+        meter.add_block(&[1.0, 0.0, -1.0, 1.5]);
+        meter.add_frame(1.0, -2.0);
+        meter.add_frame(-1.0, 0.0);
+        let levels = meter.take();
+
+        assert_eq!(levels.left.peak, 1.0);
+        assert_eq!(levels.left.rms, 1.0);
+        assert_eq!(levels.left.clipped, 0);
+        assert_eq!(levels.right.peak, 2.0);
+        assert_eq!(levels.right.clipped, 2);
+        assert_eq!(levels.clipped(), 2);
+
+        assert_eq!(meter.take(), Levels::default());
+    }
+}
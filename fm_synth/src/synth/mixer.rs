@@ -5,14 +5,69 @@ use super::voice::ProgramState;
 
 node_definition! {
     #[derive(Default, Clone, Debug)]
-    Mixer(in1, in2, mix1, mix2 => out)
+    Mixer(in1, in2, #[default] in3, mix1, mix2, #[default] mix3 => out)
 }
 impl DspNode for Mixer {
     fn next_sample(&mut self, state: &mut ProgramState) {
         self.resolve_inputs(state);
-        self.out.write(
-            self.resolved.in1 * self.resolved.mix1 + self.resolved.in2 * self.resolved.mix2,
-            state,
-        );
+        let r = &self.resolved;
+        self.out
+            .write(r.in1 * r.mix1 + r.in2 * r.mix2 + r.in3 * r.mix3, state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::synth::test_util::{constant, from_json, linked, output, state};
+
+    #[test]
+    fn weighted_sum() {
+        let mut mixer: Mixer = from_json(json!({
+            "inputs": {
+                "in1": linked(0),
+                "in2": linked(1),
+                "in3": linked(2),
+                "mix1": constant(0.5),
+                "mix2": constant(2.0),
+                "mix3": constant(-1.0),
+            },
+            "outputs": { "out": output(3) }
+        }));
+        let mut state = state(4);
+        state.links[0] = 0.8;
+        state.links[1] = -0.25;
+        state.links[2] = 0.125;
+        mixer.next_sample(&mut state);
+        assert_eq!(state.links[3], 0.8 * 0.5 - 0.25 * 2.0 - 0.125);
+    }
+
+    #[test]
+    fn two_input_patches_still_load() {
+        let mut mixer: Mixer = from_json(json!({
+            "inputs": {
+                "in1": linked(0),
+                "in2": linked(1),
+                "mix1": constant(0.5),
+                "mix2": constant(2.0),
+            },
+            "outputs": { "out": output(2) }
+        }));
+        let mut state = state(3);
+        state.links[0] = 0.8;
+        state.links[1] = -0.25;
+        mixer.next_sample(&mut state);
+        assert_eq!(state.links[2], 0.8 * 0.5 - 0.25 * 2.0);
+    }
+
+    #[test]
+    fn other_inputs_are_required() {
+        let result = serde_json::from_value::<Mixer>(json!({
+            "inputs": { "in1": linked(0), "mix1": constant(0.5), "mix2": constant(2.0) },
+            "outputs": { "out": output(2) }
+        }));
+        assert!(result.is_err());
     }
 }
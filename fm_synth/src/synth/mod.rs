@@ -1,102 +1,345 @@
-use std::iter::{repeat, repeat_with};
+use std::{
+    f64::consts::FRAC_PI_4,
+    iter::repeat_with,
+};
 
-use crossbeam_channel::Receiver;
-use rodio::Source;
+use rayon::prelude::*;
 
-pub use serialized::PatchDefinition;
+pub use allocator::VoiceAllocation;
+pub use error::PatchError;
+pub use meter::{ChannelLevel, Levels, Meter};
+pub use player::Player;
+pub use port::PortUpdate;
+pub use render::{render, TimedEvent};
+pub use sequence::Sequence;
+pub use serialized::{PatchDefinition, PatchSettings, Unison};
+pub use validate::{Diagnostic, DiagnosticKind, Severity};
+pub use waveform::{waveforms, NodeWaveform, Waveforms, WAVEFORM_FREQ};
 
-use self::voice::Program;
+use self::{allocator::VoiceAllocator, voice::Program};
 
 #[macro_use]
 mod dsp_node;
 mod adsr;
+mod allocator;
+mod crossfade;
+mod error;
+mod graph;
+mod meter;
 mod mixer;
+mod pan;
+mod player;
 mod sinosc;
 mod port;
+mod render;
+mod sequence;
 mod voice;
 mod serialized;
+mod summer;
+mod utility;
+mod validate;
+mod waveform;
 
-const SAMPLE_RATE: u32 = 44100;
+#[cfg(test)]
+mod test_util;
+
+pub const SAMPLE_RATE: u32 = 44100;
 const SAMPLE_PERIOD: f64 = 1.0 / SAMPLE_RATE as f64;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum SynthInputEvent {
     KeyDown { key: u8, freq: f64 },
     KeyUp { key: u8 },
 }
 
+impl SynthInputEvent {
+    /// Key down event for a MIDI key number, tuned to A440 equal temperament.
+    pub fn key_down(key: u8) -> Self {
+        SynthInputEvent::KeyDown {
+            key,
+            freq: key_to_freq(key),
+        }
+    }
+}
+
+fn key_to_freq(key: u8) -> f64 {
+    2.0_f64.powf((key as f64 - 69.0) / 12.0) * 440.0
+}
+
+/// (left, right) gains placing a signal at `pan`, from -1 (hard left) to 1 (hard right), with
+/// constant total power. Both gains are `FRAC_1_SQRT_2` in the center.
+pub(crate) fn equal_power_pan(pan: f64) -> (f64, f64) {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+    (angle.cos(), angle.sin())
+}
+
+/// Length of the fade out of a voice stolen for a new note, in frames (5ms).
+const STEAL_FADE_FRAMES: usize = SAMPLE_RATE as usize / 200;
+/// Largest number of frames each voice renders at a time in `Patch::fill_block`.
+const MAX_BLOCK_FRAMES: usize = 512;
+
+/// A voice being faded out before it plays a new note.
+struct Steal {
+    remaining: usize,
+    // events for the new note, applied once the fade is done
+    pending: Vec<SynthInputEvent>,
+}
+
+/// One copy of the patch within a voice, see `Unison`.
+struct UnisonCopy {
+    program: Program,
+    freq_ratio: f64,
+    // (left, right) gains placing the copy in the stereo field
+    gains: (f64, f64),
+}
+
+/// One voice of a `Patch`: a `Program` for each unison copy, and what is needed to start, steal
+/// and stop them together.
+struct Voice {
+    copies: Vec<UnisonCopy>,
+    steal: Option<Steal>,
+    // whether the voice is sounding, silent voices are not run
+    running: bool,
+    // whether the note has been released, so the voice can stop once it is silent
+    released: bool,
+    // output of the last `render_block`, empty if the voice was not running
+    block: Vec<(f64, f64)>,
+}
+
+impl Voice {
+    fn new(def: &PatchDefinition) -> Result<Self, PatchError> {
+        let copies = def
+            .settings
+            .unison
+            .copies()
+            .into_iter()
+            .map(|(freq_ratio, gains)| {
+                Ok(UnisonCopy {
+                    program: Program::new(def)?,
+                    freq_ratio,
+                    gains,
+                })
+            })
+            .collect::<Result<_, PatchError>>()?;
+        Ok(Voice {
+            copies,
+            steal: None,
+            running: false,
+            released: false,
+            block: Vec::with_capacity(MAX_BLOCK_FRAMES),
+        })
+    }
+
+    /// Play `event` on every copy, detuned by the copy's ratio.
+    fn process_event(&mut self, event: SynthInputEvent) {
+        for copy in &mut self.copies {
+            let event = match event {
+                SynthInputEvent::KeyDown { key, freq } => SynthInputEvent::KeyDown {
+                    key,
+                    freq: freq * copy.freq_ratio,
+                },
+                SynthInputEvent::KeyUp { key } => SynthInputEvent::KeyUp { key },
+            };
+            copy.program.process_event(event);
+        }
+    }
+
+    /// Play `event`, or hold on to it until the voice is faded out if it was stolen.
+    fn handle_event(&mut self, event: SynthInputEvent) {
+        match &mut self.steal {
+            Some(steal) => steal.pending.push(event),
+            None => self.process_event(event),
+        }
+    }
+
+    fn next_frame(&mut self) -> (f64, f64) {
+        if !self.running {
+            return (0.0, 0.0);
+        }
+        let mut gain = 1.0;
+        if let Some(steal) = &mut self.steal {
+            if steal.remaining == 0 {
+                // faded out, start the new note
+                let pending = std::mem::take(&mut steal.pending);
+                self.steal = None;
+                for event in pending {
+                    self.process_event(event);
+                }
+            } else {
+                steal.remaining -= 1;
+                gain = steal.remaining as f64 / STEAL_FADE_FRAMES as f64;
+                if steal.remaining == 0 {
+                    // drop the gate for a sample so the new note retriggers the envelopes
+                    for copy in &mut self.copies {
+                        copy.program.set_gate(false);
+                    }
+                }
+            }
+        }
+
+        let (mut l, mut r) = (0.0, 0.0);
+        for copy in &mut self.copies {
+            let (cl, cr) = copy.program.next_sample();
+            l += cl * copy.gains.0;
+            r += cr * copy.gains.1;
+        }
+        // copies are scaled so unison does not make the patch louder
+        gain /= self.copies.len() as f64;
+
+        if self.released && self.copies.iter().all(|c| c.program.is_silent()) {
+            self.running = false;
+        }
+        (l * gain, r * gain)
+    }
+
+    fn render_block(&mut self, frames: usize) {
+        self.block.clear();
+        for _ in 0..frames {
+            if !self.running {
+                break;
+            }
+            let frame = self.next_frame();
+            self.block.push(frame);
+        }
+    }
+}
+
+/// Default number of notes that can sound at once.
+pub const DEFAULT_VOICES: usize = 9;
+
+/// A loaded patch with several voices, ready to play.
+///
+/// `Patch` does not depend on any audio or MIDI backend. Feed it events with `handle_event` and
+/// pull stereo samples with `next_frame` or `fill_block`. `fill_block` renders the sounding
+/// voices in parallel on the rayon thread pool, and is much faster for large patches.
 pub struct Patch {
-    voices: Vec<Program>,
-    voice_assignments: Vec<Option<u8>>,
-    event_rx: Receiver<SynthInputEvent>,
-    // number used to uniquely identify this patch
-    pub index: usize,
+    voices: Vec<Voice>,
+    allocator: VoiceAllocator,
 }
 
 impl Patch {
-    pub fn new(def: PatchDefinition, event_rx: Receiver<SynthInputEvent>, index: usize) -> Self {
-        let num_voices = 9;
-        Self {
-            voices: repeat_with(|| Program::new(&def))
+    /// Load `def` with `num_voices` voices. Fails if the definition does not pass validation.
+    pub fn new(def: &PatchDefinition, num_voices: usize) -> Result<Self, PatchError> {
+        def.check()?;
+        Ok(Self {
+            voices: repeat_with(|| Voice::new(def))
                 .take(num_voices)
-                .collect(),
-            voice_assignments: repeat(None).take(num_voices).collect(),
-            event_rx,
-            index,
-        }
+                .collect::<Result<_, _>>()?,
+            allocator: VoiceAllocator::new(def.settings.voice_allocation, num_voices),
+        })
     }
 
     pub fn handle_event(&mut self, event: SynthInputEvent) {
         match event {
-            SynthInputEvent::KeyDown { key, .. } => {
-                let unused_voice_idx = self.voice_assignments.iter().position(|k| *k == None);
-                if let Some(unused_voice_idx) = unused_voice_idx {
-                    self.voices[unused_voice_idx].process_event(event);
-                    self.voice_assignments[unused_voice_idx] = Some(key);
+            SynthInputEvent::KeyDown { key, freq } => {
+                if let Some(allocation) = self.allocator.note_on(key, freq) {
+                    let voice = &mut self.voices[allocation.voice];
+                    if allocation.stolen && voice.steal.is_none() {
+                        voice.steal = Some(Steal {
+                            remaining: STEAL_FADE_FRAMES,
+                            pending: Vec::new(),
+                        });
+                    }
+                    voice.running = true;
+                    voice.released = false;
+                    voice.handle_event(event);
                 }
             }
             SynthInputEvent::KeyUp { key } => {
-                // TODO use least-recently used algorithm here so new voices dont clobber the
-                // release of current voices
-                // alternatively, let voices signal when theyre "done"
-                let voice_idx = self.voice_assignments.iter().position(|k| *k == Some(key));
-                if let Some(voice_idx) = voice_idx {
-                    self.voices[voice_idx].process_event(event);
-                    self.voice_assignments[voice_idx] = None;
+                for i in self.allocator.note_off(key) {
+                    self.voices[i].released = true;
+                    self.voices[i].handle_event(event.clone());
                 }
             }
         }
     }
-}
-
-impl Iterator for Patch {
-    type Item = f32;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        // get all events in the queue
-        while let Ok(event) = self.event_rx.try_recv() {
-            self.handle_event(event);
+    /// Change the `mult` and/or `bias` of an input port on every voice, without resetting any
+    /// state.
+    pub fn update_port(&mut self, update: &PortUpdate) -> Result<(), PatchError> {
+        for copy in self.voices.iter_mut().flat_map(|v| &mut v.copies) {
+            if !copy.program.update_port(update) {
+                return Err(PatchError::UnknownPort {
+                    node: update.node,
+                    port: update.port.clone(),
+                });
+            }
         }
-        Some(
-            self.voices.iter_mut().filter_map(|v| v.next()).sum::<f32>() / self.voices.len() as f32,
-        )
+        Ok(())
+    }
+
+    /// Number of voices currently sounding, including notes which are released but still
+    /// ringing out.
+    pub fn active_voices(&self) -> usize {
+        self.voices.iter().filter(|v| v.running).count()
+    }
+
+    pub fn num_voices(&self) -> usize {
+        self.voices.len()
+    }
+
+    /// Key down events for every note currently held, oldest first, to replay them on another
+    /// patch.
+    pub fn held_notes(&self) -> Vec<SynthInputEvent> {
+        self.allocator
+            .held_notes()
+            .into_iter()
+            .map(|(key, freq)| SynthInputEvent::KeyDown { key, freq })
+            .collect()
     }
-}
 
-impl Source for Patch {
-    fn current_frame_len(&self) -> Option<usize> {
-        None
+    /// Let the allocator reuse voices which have stopped.
+    fn release_finished_voices(&mut self) {
+        for (i, voice) in self.voices.iter().enumerate() {
+            if !voice.running {
+                self.allocator.voice_finished(i);
+            }
+        }
     }
 
-    fn channels(&self) -> u16 {
-        2
+    /// Scale the sum of all voices to a sample.
+    fn output_gain(&self) -> f64 {
+        1.0 / self.voices.len() as f64
     }
 
-    fn sample_rate(&self) -> u32 {
-        SAMPLE_RATE
+    /// Compute the next (left, right) sample pair.
+    pub fn next_frame(&mut self) -> (f32, f32) {
+        let (l, r) = self
+            .voices
+            .iter_mut()
+            .map(|v| v.next_frame())
+            .fold((0.0, 0.0), |(l, r), (vl, vr)| (l + vl, r + vr));
+        self.release_finished_voices();
+        let gain = self.output_gain();
+        ((l * gain) as f32, (r * gain) as f32)
     }
 
-    fn total_duration(&self) -> Option<std::time::Duration> {
-        None
+    /// Fill `out` with interleaved left/right samples. `out` should have an even length.
+    pub fn fill_block(&mut self, out: &mut [f32]) {
+        for chunk in out.chunks_mut(MAX_BLOCK_FRAMES * 2) {
+            let frames = chunk.len().div_ceil(2);
+            // only worth handing out to other threads if several voices are sounding
+            if self.active_voices() > 1 {
+                self.voices
+                    .par_iter_mut()
+                    .for_each(|v| v.render_block(frames));
+            } else {
+                self.voices.iter_mut().for_each(|v| v.render_block(frames));
+            }
+            self.release_finished_voices();
+
+            let gain = self.output_gain();
+            for (i, frame) in chunk.chunks_mut(2).enumerate() {
+                let (l, r) = self
+                    .voices
+                    .iter()
+                    .filter_map(|v| v.block.get(i))
+                    .fold((0.0, 0.0), |(l, r), (vl, vr)| (l + vl, r + vr));
+                frame[0] = (l * gain) as f32;
+                if let Some(right) = frame.get_mut(1) {
+                    *right = (r * gain) as f32;
+                }
+            }
+        }
     }
 }
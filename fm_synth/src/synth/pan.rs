@@ -0,0 +1,57 @@
+use crate::synth::dsp_node::DspNode;
+use crate::synth::port::{InPort, OutPort};
+
+use super::{equal_power_pan, voice::ProgramState};
+
+node_definition! {
+    #[derive(Default, Clone, Debug)]
+    Pan(input, pan => left, right)
+}
+
+impl DspNode for Pan {
+    fn next_sample(&mut self, state: &mut ProgramState) {
+        self.resolve_inputs(state);
+        let (l, r) = equal_power_pan(self.resolved.pan);
+        self.left.write(self.resolved.input * l, state);
+        self.right.write(self.resolved.input * r, state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::FRAC_1_SQRT_2;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::synth::test_util::{constant, from_json, linked, output, state};
+
+    fn pan(position: f64) -> (f64, f64) {
+        let mut pan: Pan = from_json(json!({
+            "inputs": { "input": linked(0), "pan": constant(position) },
+            "outputs": { "left": output(1), "right": output(2) }
+        }));
+        let mut state = state(3);
+        state.links[0] = 0.5;
+        pan.next_sample(&mut state);
+        (state.links[1], state.links[2])
+    }
+
+    #[test]
+    fn equal_power() {
+        let (l, r) = pan(0.0);
+        assert!((l - 0.5 * FRAC_1_SQRT_2).abs() < 1e-12);
+        assert!((r - 0.5 * FRAC_1_SQRT_2).abs() < 1e-12);
+
+        let (l, r) = pan(-1.0);
+        assert!((l - 0.5).abs() < 1e-12 && r.abs() < 1e-12);
+        // positions beyond the edges are clamped
+        let (l, r) = pan(3.0);
+        assert!(l.abs() < 1e-12 && (r - 0.5).abs() < 1e-12);
+
+        for position in [-0.7, -0.2, 0.4, 0.9] {
+            let (l, r) = pan(position);
+            assert!((l * l + r * r - 0.25).abs() < 1e-12);
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use crossbeam_channel::Sender;
+
+use super::{Patch, PatchError, PortUpdate, SynthInputEvent, SAMPLE_RATE};
+
+/// Length of the crossfade when a patch is replaced, in frames (20ms).
+const CROSSFADE_FRAMES: usize = SAMPLE_RATE as usize / 50;
+/// Most patches fading out at once. Swapping faster than that cuts off the quietest one.
+const MAX_FADING: usize = 4;
+
+/// Plays one patch at a time. Replacing the patch crossfades from the old one to the new one
+/// and carries over all held notes, so notes keep sounding while the patch is edited.
+pub struct Player {
+    patch: Option<Patch>,
+    // gain of `patch` in frames of crossfade, rising to `CROSSFADE_FRAMES` after a swap
+    level: usize,
+    // previous patches while they fade out, each with its level falling to 0
+    fading: Vec<(Patch, usize)>,
+    // where replaced patches go once they have faded out, see `retire_to`
+    retired: Option<Sender<Patch>>,
+    // output of a fading patch in `fill_block`, long enough for a whole crossfade
+    faded: Vec<f32>,
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Player {
+            patch: None,
+            level: 0,
+            // never grows, so swapping does not allocate
+            fading: Vec::with_capacity(MAX_FADING),
+            retired: None,
+            faded: vec![0.0; CROSSFADE_FRAMES * 2],
+        }
+    }
+}
+
+impl Player {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send replaced patches to `retired` once they have faded out, instead of dropping them.
+    /// Freeing a patch can take a while, so an audio thread should leave that to another thread.
+    /// Patches are still dropped in place if `retired` is full or disconnected.
+    pub fn retire_to(&mut self, retired: Sender<Patch>) {
+        self.retired = Some(retired);
+    }
+
+    /// Replace the playing patch with `patch`.
+    pub fn swap(&mut self, mut patch: Patch) {
+        match self.patch.take() {
+            Some(old) => {
+                for event in old.held_notes() {
+                    patch.handle_event(event);
+                }
+                if self.fading.len() == MAX_FADING {
+                    let quietest = (0..MAX_FADING).min_by_key(|&i| self.fading[i].1).unwrap();
+                    let (cut, _) = self.fading.remove(quietest);
+                    self.retire(cut);
+                }
+                // the old patch fades out from wherever its own fade in had got to
+                self.fading.push((old, self.level));
+                self.level = 0;
+            }
+            // nothing to fade from
+            None => self.level = CROSSFADE_FRAMES,
+        }
+        self.patch = Some(patch);
+    }
+
+    fn retire(&self, patch: Patch) {
+        if let Some(retired) = &self.retired {
+            let _ = retired.try_send(patch);
+        }
+    }
+
+    pub fn handle_event(&mut self, event: SynthInputEvent) {
+        // notes carried over to the new patch must also be released in the fading ones
+        if let SynthInputEvent::KeyUp { .. } = event {
+            for (fading, _) in &mut self.fading {
+                fading.handle_event(event.clone());
+            }
+        }
+        if let Some(patch) = &mut self.patch {
+            patch.handle_event(event);
+        }
+    }
+
+    /// Change an input port of the playing patch, see `Patch::update_port`. Patches still fading
+    /// out are changed too, so the old value is not heard during the crossfade.
+    pub fn update_port(&mut self, update: &PortUpdate) -> Result<(), PatchError> {
+        for (fading, _) in &mut self.fading {
+            // an older patch may not have the port, which is only checked against the new one
+            let _ = fading.update_port(update);
+        }
+        match &mut self.patch {
+            Some(patch) => patch.update_port(update),
+            None => Ok(()),
+        }
+    }
+
+    /// Number of voices of the playing patch which are playing a note, and the total number of
+    /// voices. Both are 0 before the first patch.
+    pub fn voice_activity(&self) -> (usize, usize) {
+        match &self.patch {
+            Some(patch) => (patch.active_voices(), patch.num_voices()),
+            None => (0, 0),
+        }
+    }
+
+    /// Compute the next (left, right) sample pair.
+    pub fn next_frame(&mut self) -> (f32, f32) {
+        let (mut l, mut r) = (0.0, 0.0);
+        if let Some(patch) = &mut self.patch {
+            let gain = self.level as f32 / CROSSFADE_FRAMES as f32;
+            let (pl, pr) = patch.next_frame();
+            l += pl * gain;
+            r += pr * gain;
+            self.level = (self.level + 1).min(CROSSFADE_FRAMES);
+        }
+
+        for (fading, level) in &mut self.fading {
+            let gain = *level as f32 / CROSSFADE_FRAMES as f32;
+            let (fl, fr) = fading.next_frame();
+            l += fl * gain;
+            r += fr * gain;
+            *level = level.saturating_sub(1);
+        }
+        while let Some(i) = self.fading.iter().position(|(_, level)| *level == 0) {
+            let (faded, _) = self.fading.remove(i);
+            self.retire(faded);
+        }
+
+        (l, r)
+    }
+
+    /// Fill `out` with interleaved left/right samples. `out` should have an even length.
+    /// Renders the voices in parallel, see `Patch::fill_block`.
+    pub fn fill_block(&mut self, out: &mut [f32]) {
+        match &mut self.patch {
+            Some(patch) => {
+                patch.fill_block(out);
+                for frame in out.chunks_mut(2) {
+                    let gain = self.level as f32 / CROSSFADE_FRAMES as f32;
+                    frame.iter_mut().for_each(|s| *s *= gain);
+                    self.level = (self.level + 1).min(CROSSFADE_FRAMES);
+                }
+            }
+            None => out.fill(0.0),
+        }
+
+        for (fading, level) in &mut self.fading {
+            // a fading patch is only rendered until its level reaches 0
+            let faded = &mut self.faded[..out.len().min(*level * 2)];
+            fading.fill_block(faded);
+            for (frame, faded) in out.chunks_mut(2).zip(faded.chunks(2)) {
+                let gain = *level as f32 / CROSSFADE_FRAMES as f32;
+                for (s, f) in frame.iter_mut().zip(faded) {
+                    *s += f * gain;
+                }
+                *level -= 1;
+            }
+        }
+        while let Some(i) = self.fading.iter().position(|(_, level)| *level == 0) {
+            let (faded, _) = self.fading.remove(i);
+            self.retire(faded);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::synth::{
+        test_util::{constant, from_json, linked, output},
+        PatchDefinition,
+    };
+
+    /// Sine oscillator gated directly by the key, with volume `vol`
+    fn patch(vol: f64) -> Patch {
+        let def: PatchDefinition = from_json(json!({
+            "nodes": [{
+                "type": "sinosc",
+                "inputs": {
+                    "freq": linked(0),
+                    "phase": constant(0.0),
+                    "vol": { "mult": vol, "bias": 0.0, "link": 1 },
+                    "feedback": constant(0.0),
+                },
+                "outputs": { "out": output(2) }
+            }],
+            "io": { "freq": 0, "gate": 1, "lchan": 2, "rchan": 2 }
+        }));
+        Patch::new(&def, 1).unwrap()
+    }
+
+    fn run(player: &mut Player, n: usize) -> Vec<f32> {
+        (0..n).map(|_| player.next_frame().0).collect()
+    }
+
+    /// Largest change between two consecutive samples. A 440Hz sine changes by at most
+    /// 2 * pi * 440 / 44100 per sample at full volume.
+    fn max_step(out: &[f32]) -> f32 {
+        out.windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0.0, f32::max)
+    }
+
+    fn peak(out: &[f32]) -> f32 {
+        out.iter().fold(0.0, |peak: f32, x| peak.max(x.abs()))
+    }
+
+    #[test]
+    fn swap_is_continuous_and_keeps_held_notes() {
+        let mut player = Player::new();
+        player.swap(patch(1.0));
+        player.handle_event(SynthInputEvent::key_down(69));
+        let mut out = run(&mut player, 1000);
+
+        player.swap(patch(0.5));
+        out.extend(run(&mut player, 2 * CROSSFADE_FRAMES));
+
+        assert!(max_step(&out) < 0.07, "discontinuity of {}", max_step(&out));
+
+        // the held note now plays on the new patch only
+        assert!((peak(&out[out.len() - 200..]) - 0.5).abs() < 0.01);
+
+        player.handle_event(SynthInputEvent::KeyUp { key: 69 });
+        assert!(run(&mut player, 10).iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn swaps_during_a_crossfade_are_continuous() {
+        let (retired_tx, retired_rx) = crossbeam_channel::bounded(MAX_FADING + 2);
+        let mut player = Player::new();
+        player.retire_to(retired_tx);
+        player.swap(patch(1.0));
+        player.handle_event(SynthInputEvent::key_down(69));
+        let mut out = run(&mut player, 1000);
+
+        // more swaps than can fade at once, each before the previous crossfade is done
+        for i in 0..=MAX_FADING + 1 {
+            player.swap(patch(1.0 - 0.1 * i as f64));
+            out.extend(run(&mut player, CROSSFADE_FRAMES / 3));
+        }
+        out.extend(run(&mut player, 2 * CROSSFADE_FRAMES));
+        assert!(max_step(&out) < 0.07, "discontinuity of {}", max_step(&out));
+        assert!((peak(&out[out.len() - 200..]) - 0.5).abs() < 0.01);
+
+        // every replaced patch was handed back rather than dropped
+        assert_eq!(retired_rx.try_iter().count(), MAX_FADING + 2);
+    }
+
+    #[test]
+    fn blocks_match_single_frames() {
+        let mut by_frame = Player::new();
+        let mut by_block = Player::new();
+        let mut block = vec![0.0; 2 * 300];
+        for player in [&mut by_frame, &mut by_block] {
+            player.swap(patch(1.0));
+            for key in [60, 64, 67] {
+                player.handle_event(SynthInputEvent::key_down(key));
+            }
+        }
+
+        let mut expected = Vec::new();
+        let mut actual = Vec::new();
+        for vol in [0.5, 0.8] {
+            // the second swap comes during the first crossfade, so two patches fade out at once
+            for player in [&mut by_frame, &mut by_block] {
+                player.swap(patch(vol));
+                player.handle_event(SynthInputEvent::KeyUp { key: 64 });
+            }
+            for _ in 0..block.len() / 2 {
+                let (l, r) = by_frame.next_frame();
+                expected.extend([l, r]);
+            }
+            by_block.fill_block(&mut block);
+            actual.extend_from_slice(&block);
+        }
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn port_update_keeps_state() {
+        let mut player = Player::new();
+        player.swap(patch(1.0));
+        player.handle_event(SynthInputEvent::key_down(69));
+        let mut out = run(&mut player, 1000);
+
+        let update = PortUpdate {
+            node: 0,
+            port: "vol".to_string(),
+            mult: Some(0.5),
+            bias: None,
+        };
+        player.update_port(&update).unwrap();
+        out.extend(run(&mut player, 200));
+
+        // the oscillator carries on from the same phase at half the volume
+        let ratio = out[1000] / out[999];
+        let expected = 0.5 * (2.0 * PI * 440.0 * 1001.0 / 44100.0).sin()
+            / (2.0 * PI * 440.0 * 1000.0 / 44100.0).sin();
+        assert!((ratio as f64 - expected).abs() < 1e-3);
+
+        let unknown = PortUpdate {
+            port: "volume".to_string(),
+            ..update
+        };
+        assert_eq!(
+            player.update_port(&unknown),
+            Err(PatchError::UnknownPort {
+                node: 0,
+                port: "volume".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn port_update_during_a_crossfade_changes_both_patches() {
+        let mut player = Player::new();
+        player.swap(patch(1.0));
+        player.handle_event(SynthInputEvent::key_down(69));
+        run(&mut player, 1000);
+
+        player.swap(patch(1.0));
+        let update = PortUpdate {
+            node: 0,
+            port: "vol".to_string(),
+            mult: Some(0.5),
+            bias: None,
+        };
+        player.update_port(&update).unwrap();
+        // the patch fading out is not heard at its old volume
+        let out = run(&mut player, CROSSFADE_FRAMES);
+        assert!(peak(&out) < 0.51, "peak of {}", peak(&out));
+    }
+}
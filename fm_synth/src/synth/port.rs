@@ -1,13 +1,31 @@
+use std::num::NonZeroUsize;
+
 use serde::Deserialize;
 
 use crate::synth::voice::ProgramState;
 
+/// A change to the `mult` and/or `bias` of a single input port, addressed by node index (into
+/// `PatchDefinition::nodes`) and port name.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PortUpdate {
+    pub node: usize,
+    pub port: String,
+    #[serde(default)]
+    pub mult: Option<f64>,
+    #[serde(default)]
+    pub bias: Option<f64>,
+}
+
 #[derive(Deserialize, Clone, Debug, Default)]
 pub struct InPort {
     mult: f64,
     bias: f64,
     #[serde(skip_serializing_if = "Option::is_none")]
     link: Option<usize>,
+    /// Read the link as it was this many samples ago. Delayed (weak) links are ignored when
+    /// ordering nodes, which allows feedback loops.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delay: Option<NonZeroUsize>,
 }
 
 #[derive(Deserialize, Clone, Debug, Default)]
@@ -17,6 +35,36 @@ pub struct OutPort {
     link: Option<usize>,
 }
 
+impl InPort {
+    /// Index of the link this port reads from, if connected.
+    pub fn link(&self) -> Option<usize> {
+        self.link
+    }
+
+    pub fn mult(&self) -> f64 {
+        self.mult
+    }
+
+    pub fn bias(&self) -> f64 {
+        self.bias
+    }
+
+    /// Apply the `mult` and `bias` of `update`, leaving values it does not set unchanged.
+    pub fn apply(&mut self, update: &PortUpdate) {
+        if let Some(mult) = update.mult {
+            self.mult = mult;
+        }
+        if let Some(bias) = update.bias {
+            self.bias = bias;
+        }
+    }
+
+    /// Sample delay of this port, if it reads a delayed (weak) link.
+    pub fn delay(&self) -> Option<usize> {
+        self.delay.map(NonZeroUsize::get)
+    }
+}
+
 pub trait Port {
     fn read(&self, state: &ProgramState) -> f64;
 }
@@ -24,9 +72,10 @@ pub trait Port {
 impl Port for InPort {
     #[inline]
     fn read(&self, state: &ProgramState) -> f64 {
-        match self.link {
-            None => self.bias,
-            Some(i) => state.links[i] * self.mult + self.bias,
+        match (self.link, self.delay) {
+            (None, _) => self.bias,
+            (Some(i), None) => state.links[i] * self.mult + self.bias,
+            (Some(i), Some(delay)) => state.delayed(i, delay.get()) * self.mult + self.bias,
         }
     }
 }
@@ -42,6 +91,11 @@ impl Port for OutPort {
 }
 
 impl OutPort {
+    /// Index of the link this port writes to, if connected.
+    pub fn link(&self) -> Option<usize> {
+        self.link
+    }
+
     #[inline]
     pub fn write(&self, val: f64, state: &mut ProgramState) {
         if let Some(i) = self.link {
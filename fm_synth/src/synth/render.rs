@@ -0,0 +1,29 @@
+use super::{Patch, SynthInputEvent};
+
+/// An event scheduled at a given sample (frame) index.
+#[derive(Debug, Clone)]
+pub struct TimedEvent {
+    pub sample: u64,
+    pub event: SynthInputEvent,
+}
+
+/// Render `num_frames` stereo frames of `patch`, applying each event at the start of its sample.
+/// `events` must be sorted by sample. Returns interleaved left/right samples.
+pub fn render(patch: &mut Patch, events: &[TimedEvent], num_frames: u64) -> Vec<f32> {
+    let mut out = vec![0.0; num_frames as usize * 2];
+    let mut events = events.iter().peekable();
+    let mut frame = 0;
+    while frame < num_frames {
+        while let Some(timed) = events.next_if(|e| e.sample <= frame) {
+            patch.handle_event(timed.event.clone());
+        }
+        // render in one go up to the next event
+        let end = events
+            .peek()
+            .map(|e| e.sample.min(num_frames))
+            .unwrap_or(num_frames);
+        patch.fill_block(&mut out[frame as usize * 2..end as usize * 2]);
+        frame = end;
+    }
+    out
+}
@@ -0,0 +1,79 @@
+use super::TimedEvent;
+
+/// Events played on the frame clock of whoever renders them, so each one starts on its exact
+/// frame even when frames are rendered in blocks.
+pub struct Sequence {
+    // sorted by sample
+    events: Vec<TimedEvent>,
+    // index of the next event to play
+    next: usize,
+    // frames played so far
+    clock: u64,
+}
+
+impl Sequence {
+    /// `events` must be sorted by sample, counting from the first frame played.
+    pub fn new(events: Vec<TimedEvent>) -> Self {
+        Self {
+            events,
+            next: 0,
+            clock: 0,
+        }
+    }
+
+    /// Events due at the current frame, in order. Each event is only returned once.
+    pub fn due(&mut self) -> &[TimedEvent] {
+        let start = self.next;
+        self.next += self.events[start..].partition_point(|e| e.sample <= self.clock);
+        &self.events[start..self.next]
+    }
+
+    /// Frames from the current one until the next event is due, `None` once every event has been
+    /// played.
+    pub fn frames_to_next(&self) -> Option<u64> {
+        self.events
+            .get(self.next)
+            .map(|e| e.sample.saturating_sub(self.clock))
+    }
+
+    /// Move the clock on by `frames` played frames.
+    pub fn advance(&mut self, frames: u64) {
+        self.clock += frames;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::SynthInputEvent;
+
+    fn key_up(sample: u64, key: u8) -> TimedEvent {
+        TimedEvent {
+            sample,
+            event: SynthInputEvent::KeyUp { key },
+        }
+    }
+
+    fn keys(events: &[TimedEvent]) -> Vec<u8> {
+        events
+            .iter()
+            .map(|timed| match timed.event {
+                SynthInputEvent::KeyUp { key } | SynthInputEvent::KeyDown { key, .. } => key,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn events_are_due_on_their_frame() {
+        let mut sequence = Sequence::new(vec![key_up(0, 1), key_up(2, 2), key_up(2, 3)]);
+        assert_eq!(keys(sequence.due()), vec![1]);
+        assert!(sequence.due().is_empty());
+        assert_eq!(sequence.frames_to_next(), Some(2));
+
+        // frames rendered in one go, up to the next event
+        sequence.advance(2);
+        assert_eq!(sequence.frames_to_next(), Some(0));
+        assert_eq!(keys(sequence.due()), vec![2, 3]);
+        assert_eq!(sequence.frames_to_next(), None);
+    }
+}
@@ -1,20 +1,222 @@
+use std::f64::consts::SQRT_2;
+
 use serde::Deserialize;
 
-use super::{adsr::Adsr, mixer::Mixer, sinosc::SinOsc};
+use super::{
+    adsr::Adsr,
+    allocator::VoiceAllocation,
+    crossfade::Crossfade,
+    dsp_node::DspNode,
+    equal_power_pan,
+    error::PatchError,
+    mixer::Mixer,
+    pan::Pan,
+    port::PortUpdate,
+    sinosc::SinOsc,
+    summer::Summer,
+    utility::{Abs, Bipolar, Clamp, Max, Min, Multiply, Unipolar},
+};
+
+/// Link indices must be below this. Guards against a malformed patch making every voice
+/// allocate an enormous link buffer.
+pub const MAX_LINKS: usize = 4096;
+
+/// Port delays must be at most this many samples (1 second). Every delayed link keeps this many
+/// past samples in every voice.
+pub const MAX_DELAY: usize = super::SAMPLE_RATE as usize;
+
+/// Unison counts must be at most this, see `PatchDefinition::validate`. Every copy is a whole
+/// program in every voice.
+pub const MAX_UNISON: usize = 16;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Clone, Debug)]
 pub struct PatchDefinition {
     pub nodes: Vec<DspNodeEnum>,
     pub io: IO,
+    #[serde(default)]
+    pub settings: PatchSettings,
+}
+
+/// Options for the patch as a whole. Every option has a default, so `settings` can be left out.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct PatchSettings {
+    pub voice_allocation: VoiceAllocation,
+    /// Index of the envelope node controlling the volume of the patch. A released voice stops
+    /// running as soon as this envelope is idle. Without it, a voice stops once all envelopes
+    /// are idle and the output has been near silent for a moment.
+    pub amp_envelope: Option<usize>,
+    pub unison: Unison,
+}
+
+/// Several copies of the patch played for every note, slightly detuned and spread across the
+/// stereo field.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Unison {
+    /// Number of copies, 1 turns unison off. At most `MAX_UNISON`.
+    pub count: usize,
+    /// Distance between the lowest and highest copy, in cents
+    pub detune: f64,
+    /// How far apart the copies are panned, from 0 (all centered, each keeps its own left and
+    /// right output) to 1 (lowest hard left, highest hard right)
+    pub spread: f64,
+}
+
+impl Default for Unison {
+    fn default() -> Self {
+        Unison {
+            count: 1,
+            detune: 0.0,
+            spread: 0.0,
+        }
+    }
 }
 
-#[derive(Deserialize, Debug)]
+impl Unison {
+    /// Frequency ratio and (left, right) gains of each copy.
+    pub fn copies(&self) -> Vec<(f64, (f64, f64))> {
+        if self.count == 1 {
+            return vec![(1.0, (1.0, 1.0))];
+        }
+        (0..self.count)
+            .map(|i| {
+                // -1 for the lowest copy to 1 for the highest
+                let position = 2.0 * i as f64 / (self.count - 1) as f64 - 1.0;
+                let ratio = 2.0_f64.powf(position * self.detune / 2.0 / 1200.0);
+                // scaled up from equal power so a centered copy is as loud as without spread
+                let (left, right) = equal_power_pan(position * self.spread);
+                (ratio, (left * SQRT_2, right * SQRT_2))
+            })
+            .collect()
+    }
+}
+
+impl PatchDefinition {
+    /// All link indices referenced by the patch, paired with the index of the node referencing
+    /// them. `None` is used for links referenced by `io`.
+    pub fn links(&self) -> Vec<(Option<usize>, usize)> {
+        let mut links = Vec::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let node = node.as_node();
+            let inputs = node.inputs().into_iter().filter_map(|(_, p)| p.link());
+            let outputs = node.outputs().into_iter().filter_map(|(_, p)| p.link());
+            links.extend(inputs.chain(outputs).map(|link| (Some(i), link)));
+        }
+        let io = &self.io;
+        links.extend(
+            [io.freq, io.gate, io.lchan, io.rchan]
+                .iter()
+                .flatten()
+                .map(|&link| (None, link)),
+        );
+        links
+    }
+
+    /// Apply `update` to the definition. Nothing is changed if the port does not exist or the
+    /// resulting patch would not be valid.
+    pub fn update_port(&mut self, update: &PortUpdate) -> Result<(), PatchError> {
+        let mut updated = self.clone();
+        updated
+            .nodes
+            .get_mut(update.node)
+            .and_then(|n| n.as_node_mut().input_mut(&update.port))
+            .ok_or_else(|| PatchError::UnknownPort {
+                node: update.node,
+                port: update.port.clone(),
+            })?
+            .apply(update);
+        updated.check()?;
+        *self = updated;
+        Ok(())
+    }
+
+    /// Number of link slots needed to run this patch, ie. one more than the highest link index.
+    pub fn num_links(&self) -> Result<usize, PatchError> {
+        let links = self.links();
+        if let Some(&(node, link)) = links.iter().find(|(_, link)| *link >= MAX_LINKS) {
+            return Err(PatchError::LinkOutOfRange { node, link });
+        }
+        Ok(links.iter().map(|(_, link)| link + 1).max().unwrap_or(0))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
 #[serde(rename_all = "lowercase")]
 #[serde(tag = "type")]
 pub enum DspNodeEnum {
     Adsr(Adsr),
     SinOsc(SinOsc),
     Mixer(Mixer),
+    Crossfade(Crossfade),
+    Pan(Pan),
+    Summer(Summer),
+    Unipolar(Unipolar),
+    Bipolar(Bipolar),
+    Clamp(Clamp),
+    Abs(Abs),
+    Multiply(Multiply),
+    Min(Min),
+    Max(Max),
+}
+
+impl DspNodeEnum {
+    /// Borrow the contained node as a trait object.
+    pub fn as_node(&self) -> &(dyn DspNode + Send) {
+        match self {
+            DspNodeEnum::Adsr(x) => x,
+            DspNodeEnum::SinOsc(x) => x,
+            DspNodeEnum::Mixer(x) => x,
+            DspNodeEnum::Crossfade(x) => x,
+            DspNodeEnum::Pan(x) => x,
+            DspNodeEnum::Summer(x) => x,
+            DspNodeEnum::Unipolar(x) => x,
+            DspNodeEnum::Bipolar(x) => x,
+            DspNodeEnum::Clamp(x) => x,
+            DspNodeEnum::Abs(x) => x,
+            DspNodeEnum::Multiply(x) => x,
+            DspNodeEnum::Min(x) => x,
+            DspNodeEnum::Max(x) => x,
+        }
+    }
+
+    /// Mutably borrow the contained node as a trait object.
+    pub fn as_node_mut(&mut self) -> &mut (dyn DspNode + Send) {
+        match self {
+            DspNodeEnum::Adsr(x) => x,
+            DspNodeEnum::SinOsc(x) => x,
+            DspNodeEnum::Mixer(x) => x,
+            DspNodeEnum::Crossfade(x) => x,
+            DspNodeEnum::Pan(x) => x,
+            DspNodeEnum::Summer(x) => x,
+            DspNodeEnum::Unipolar(x) => x,
+            DspNodeEnum::Bipolar(x) => x,
+            DspNodeEnum::Clamp(x) => x,
+            DspNodeEnum::Abs(x) => x,
+            DspNodeEnum::Multiply(x) => x,
+            DspNodeEnum::Min(x) => x,
+            DspNodeEnum::Max(x) => x,
+        }
+    }
+
+    /// Clone the contained node into a trait object that can be run by a `Program`.
+    pub fn to_boxed(&self) -> Box<dyn DspNode + Send> {
+        match self {
+            DspNodeEnum::Adsr(x) => Box::new(x.clone()),
+            DspNodeEnum::SinOsc(x) => Box::new(x.clone()),
+            DspNodeEnum::Mixer(x) => Box::new(x.clone()),
+            DspNodeEnum::Crossfade(x) => Box::new(x.clone()),
+            DspNodeEnum::Pan(x) => Box::new(x.clone()),
+            DspNodeEnum::Summer(x) => Box::new(x.clone()),
+            DspNodeEnum::Unipolar(x) => Box::new(x.clone()),
+            DspNodeEnum::Bipolar(x) => Box::new(x.clone()),
+            DspNodeEnum::Clamp(x) => Box::new(x.clone()),
+            DspNodeEnum::Abs(x) => Box::new(x.clone()),
+            DspNodeEnum::Multiply(x) => Box::new(x.clone()),
+            DspNodeEnum::Min(x) => Box::new(x.clone()),
+            DspNodeEnum::Max(x) => Box::new(x.clone()),
+        }
+    }
 }
 
 #[derive(Deserialize, Default, Clone, Debug)]
@@ -30,3 +232,27 @@ pub struct IO {
     pub rchan: Option<usize>,
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unison(count: usize, spread: f64) -> Unison {
+        Unison {
+            count,
+            detune: 10.0,
+            spread,
+        }
+    }
+
+    #[test]
+    fn spread_keeps_the_level_of_centered_copies() {
+        for spread in [0.0, 0.5, 1.0] {
+            let (_, (left, right)) = unison(3, spread).copies()[1];
+            assert!((left - 1.0).abs() < 1e-9 && (right - 1.0).abs() < 1e-9);
+        }
+        // the outer copies keep the same power as they are panned apart
+        let (_, (left, right)) = unison(3, 1.0).copies()[0];
+        assert!((left * left + right * right - 2.0).abs() < 1e-9);
+        assert!(right.abs() < 1e-9);
+    }
+}
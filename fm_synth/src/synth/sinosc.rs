@@ -11,6 +11,8 @@ node_definition! {
     #[derive(Default, Clone, Debug)]
     SinOsc(freq, phase, vol, feedback => out) {
         frequency_integral: f64,
+        /// The two most recent output samples, used for self-feedback.
+        prev_out: [f64; 2],
     }
 }
 
@@ -18,9 +20,80 @@ impl DspNode for SinOsc {
     fn next_sample(&mut self, state: &mut ProgramState) {
         self.resolve_inputs(state);
         self.frequency_integral += self.resolved.freq * SAMPLE_PERIOD;
-        let out =
-            self.resolved.vol * (2.0 * PI * self.frequency_integral + self.resolved.phase).sin();
+        // DX style self-feedback. Averaging the last two samples damps the oscillation between
+        // alternating values that high feedback amounts otherwise produce.
+        let feedback = self.resolved.feedback * (self.prev_out[0] + self.prev_out[1]) / 2.0;
+        let out = self.resolved.vol
+            * (2.0 * PI * self.frequency_integral + self.resolved.phase + feedback).sin();
 
+        self.prev_out = [out, self.prev_out[0]];
         self.out.write(out, state);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::synth::{
+        test_util::{constant, from_json, output, state},
+        SAMPLE_RATE,
+    };
+
+    const OUT: usize = 0;
+
+    fn osc(freq: f64, phase: f64, feedback: Value) -> SinOsc {
+        from_json(json!({
+            "inputs": {
+                "freq": constant(freq),
+                "phase": constant(phase),
+                "vol": constant(0.5),
+                "feedback": feedback,
+            },
+            "outputs": { "out": output(OUT) }
+        }))
+    }
+
+    fn run(osc: &mut SinOsc, n: usize) -> Vec<f64> {
+        let mut state = state(1);
+        (0..n)
+            .map(|_| {
+                osc.next_sample(&mut state);
+                state.links[OUT]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frequency_and_volume() {
+        // period of exactly 100 samples
+        let freq = SAMPLE_RATE as f64 / 100.0;
+        let out = run(&mut osc(freq, 0.0, constant(0.0)), 200);
+        for (i, x) in out.iter().enumerate() {
+            let expected = 0.5 * (2.0 * PI * (i + 1) as f64 / 100.0).sin();
+            assert!((x - expected).abs() < 1e-9, "sample {}", i);
+        }
+        assert!((out[0] - out[100]).abs() < 1e-9);
+    }
+
+    #[test]
+    fn phase_offset() {
+        let out = run(&mut osc(0.0, PI / 2.0, constant(0.0)), 10);
+        assert!(out.iter().all(|x| (x - 0.5).abs() < 1e-9));
+    }
+
+    #[test]
+    fn feedback_adds_average_of_previous_outputs_to_phase() {
+        let freq = SAMPLE_RATE as f64 / 100.0;
+        let out = run(&mut osc(freq, 0.0, constant(2.0)), 3);
+
+        let phase = |i: usize| 2.0 * PI * (i + 1) as f64 / 100.0;
+        // no previous output on the first sample
+        assert!((out[0] - 0.5 * phase(0).sin()).abs() < 1e-9);
+        let expected = 0.5 * (phase(1) + 2.0 * out[0] / 2.0).sin();
+        assert!((out[1] - expected).abs() < 1e-9);
+        let expected = 0.5 * (phase(2) + 2.0 * (out[1] + out[0]) / 2.0).sin();
+        assert!((out[2] - expected).abs() < 1e-9);
+    }
+}
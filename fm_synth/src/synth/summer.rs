@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+
+use serde::{de::Error, Deserialize, Deserializer};
+
+use crate::synth::dsp_node::{DspNode, NodePorts};
+use crate::synth::port::{InPort, OutPort, Port};
+
+use super::voice::ProgramState;
+
+/// Sums any number of inputs, named `in1` to `inN` in the patch. Each input is weighted by the
+/// `mult` of its port, so this replaces chains of mixers for large operator stacks.
+///
+/// Unlike the other nodes this is not defined with `node_definition!`, as the number of inputs
+/// is only known once the patch is loaded.
+#[derive(Default, Clone, Debug)]
+pub struct Summer {
+    inputs: Vec<InPort>,
+    out: OutPort,
+}
+
+fn input_name(i: usize) -> String {
+    format!("in{}", i + 1)
+}
+
+/// Index of the input called `name`, the inverse of `input_name`.
+fn input_index(name: &str) -> Option<usize> {
+    let number: usize = name.strip_prefix("in")?.parse().ok()?;
+    let i = number.checked_sub(1)?;
+    // rejects other spellings of the number, such as "in01"
+    Some(i).filter(|&i| input_name(i) == name)
+}
+
+impl NodePorts for Summer {
+    fn inputs(&self) -> Vec<(String, &InPort)> {
+        self.inputs
+            .iter()
+            .enumerate()
+            .map(|(i, port)| (input_name(i), port))
+            .collect()
+    }
+
+    fn outputs(&self) -> Vec<(String, &OutPort)> {
+        vec![("out".to_string(), &self.out)]
+    }
+
+    fn input_mut(&mut self, name: &str) -> Option<&mut InPort> {
+        self.inputs.get_mut(input_index(name)?)
+    }
+}
+
+impl<'de> Deserialize<'de> for Summer {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Outputs {
+            out: OutPort,
+        }
+
+        #[derive(Deserialize)]
+        struct UnFlattened {
+            inputs: BTreeMap<String, InPort>,
+            outputs: Outputs,
+        }
+
+        let mut unflattened = UnFlattened::deserialize(deserializer)?;
+        let unknown: Vec<&str> = unflattened
+            .inputs
+            .keys()
+            .filter(|name| input_index(name).is_none())
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            return Err(D::Error::custom(format!(
+                "unknown summer inputs {}, inputs must be named in1, in2, ...",
+                unknown.join(", ")
+            )));
+        }
+        let count = unflattened.inputs.len();
+        if count == 0 {
+            return Err(D::Error::custom("summer needs at least one input"));
+        }
+        let inputs = (0..count)
+            .map(|i| {
+                unflattened.inputs.remove(&input_name(i)).ok_or_else(|| {
+                    D::Error::custom(format!(
+                        "summer inputs must be named in1 to in{}, missing {}",
+                        count,
+                        input_name(i)
+                    ))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Summer {
+            inputs,
+            out: unflattened.outputs.out,
+        })
+    }
+}
+
+impl DspNode for Summer {
+    fn next_sample(&mut self, state: &mut ProgramState) {
+        let sum = self.inputs.iter().map(|port| port.read(state)).sum();
+        self.out.write(sum, state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::synth::test_util::{constant, from_json, linked, output, state};
+
+    #[test]
+    fn sums_all_inputs() {
+        let mut summer: Summer = from_json(json!({
+            "inputs": {
+                "in1": linked(0),
+                "in2": { "mult": 0.5, "bias": 0.0, "link": 1 },
+                "in3": linked(2),
+                "in4": constant(0.25),
+            },
+            "outputs": { "out": output(3) }
+        }));
+        assert_eq!(summer.inputs().len(), 4);
+        assert!(summer.input_mut("in4").is_some());
+        assert!(summer.input_mut("in5").is_none());
+        assert!(summer.input_mut("in0").is_none());
+
+        let mut state = state(4);
+        state.links[0] = 1.0;
+        state.links[1] = 2.0;
+        state.links[2] = -0.5;
+        summer.next_sample(&mut state);
+        assert_eq!(state.links[3], 1.0 + 1.0 - 0.5 + 0.25);
+    }
+
+    #[test]
+    fn inputs_must_be_numbered_from_one() {
+        let result = serde_json::from_value::<Summer>(json!({
+            "inputs": { "in1": linked(0), "in3": linked(1) },
+            "outputs": { "out": output(2) }
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn unknown_inputs_are_named() {
+        let error = serde_json::from_value::<Summer>(json!({
+            "inputs": { "in1": linked(0), "mix1": constant(0.5), "in01": linked(1) },
+            "outputs": { "out": output(2) }
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("in01, mix1"), "{}", error);
+    }
+}
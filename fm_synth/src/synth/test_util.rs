@@ -0,0 +1,31 @@
+//! Helpers for building nodes and patches in tests.
+
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use super::voice::ProgramState;
+
+/// Input port reading `link` unchanged.
+pub fn linked(link: usize) -> Value {
+    json!({ "mult": 1.0, "bias": 0.0, "link": link })
+}
+
+/// Unconnected input port with a constant value.
+pub fn constant(value: f64) -> Value {
+    json!({ "mult": 1.0, "bias": value })
+}
+
+/// Output port writing to `link`.
+pub fn output(link: usize) -> Value {
+    json!({ "link": link })
+}
+
+/// Deserialize a node (or patch) from JSON, panicking on failure.
+pub fn from_json<T: DeserializeOwned>(value: Value) -> T {
+    serde_json::from_value(value).expect("invalid test JSON")
+}
+
+/// Link state with every link zeroed.
+pub fn state(num_links: usize) -> ProgramState {
+    ProgramState::new(num_links)
+}
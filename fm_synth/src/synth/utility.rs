@@ -0,0 +1,130 @@
+//! Small nodes reshaping signals, which would otherwise have to be faked with the `mult` and
+//! `bias` of ports. Each writes a single `out` computed from the current value of its inputs.
+
+pub use self::{
+    abs::Abs, bipolar::Bipolar, clamp::Clamp, max::Max, min::Min, multiply::Multiply,
+    unipolar::Unipolar,
+};
+
+/// Define a node in its own module (`node_definition!` must be the only one in its module), with
+/// the output computed by an expression of its inputs.
+macro_rules! utility_node {
+    (
+        $(#[$attribute:meta])*
+        $module:ident::$structName:ident($($inputName:ident),+) => $out:expr
+    ) => {
+        mod $module {
+            use crate::synth::dsp_node::DspNode;
+            use crate::synth::port::{InPort, OutPort};
+            use crate::synth::voice::ProgramState;
+
+            node_definition! {
+                $(#[$attribute])*
+                #[derive(Default, Clone, Debug)]
+                $structName($($inputName),+ => out)
+            }
+
+            impl DspNode for $structName {
+                fn next_sample(&mut self, state: &mut ProgramState) {
+                    self.resolve_inputs(state);
+                    $( let $inputName = self.resolved.$inputName; )+
+                    self.out.write($out, state);
+                }
+            }
+        }
+    };
+}
+
+utility_node! {
+    /// Maps a bipolar signal (-1 to 1, eg. an oscillator) to unipolar (0 to 1)
+    unipolar::Unipolar(input) => input * 0.5 + 0.5
+}
+
+utility_node! {
+    /// Maps a unipolar signal (0 to 1, eg. an envelope) to bipolar (-1 to 1)
+    bipolar::Bipolar(input) => input * 2.0 - 1.0
+}
+
+utility_node! {
+    /// Limits `input` to between `min` and `max`. If `min` is above `max`, the output is `max`.
+    clamp::Clamp(input, min, max) => input.max(min).min(max)
+}
+
+utility_node! {
+    /// Absolute value, eg. to full-wave rectify an oscillator
+    abs::Abs(input) => input.abs()
+}
+
+utility_node! {
+    /// Product of two signals, for ring modulation or a signal controlled amplifier
+    multiply::Multiply(a, b) => a * b
+}
+
+utility_node! {
+    /// Smaller of two signals
+    min::Min(a, b) => a.min(b)
+}
+
+utility_node! {
+    /// Larger of two signals
+    max::Max(a, b) => a.max(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::de::DeserializeOwned;
+    use serde_json::{json, Map};
+
+    use super::*;
+    use crate::synth::dsp_node::DspNode;
+    use crate::synth::test_util::{constant, from_json, output, state};
+
+    /// Run a node once, with constant `inputs`, returning its output.
+    fn run<T: DspNode + DeserializeOwned>(inputs: &[(&str, f64)]) -> f64 {
+        let inputs: Map<_, _> = inputs
+            .iter()
+            .map(|&(name, value)| (name.to_string(), constant(value)))
+            .collect();
+        let mut node: T = from_json(json!({
+            "inputs": inputs,
+            "outputs": { "out": output(0) }
+        }));
+        let mut state = state(1);
+        node.next_sample(&mut state);
+        state.links[0]
+    }
+
+    #[test]
+    fn polarity() {
+        assert_eq!(run::<Unipolar>(&[("input", -1.0)]), 0.0);
+        assert_eq!(run::<Unipolar>(&[("input", 0.5)]), 0.75);
+        assert_eq!(run::<Bipolar>(&[("input", 0.0)]), -1.0);
+        assert_eq!(run::<Bipolar>(&[("input", 0.75)]), 0.5);
+    }
+
+    #[test]
+    fn clamp() {
+        let clamp = |input| run::<Clamp>(&[("input", input), ("min", -0.5), ("max", 0.25)]);
+        assert_eq!(clamp(-1.0), -0.5);
+        assert_eq!(clamp(0.1), 0.1);
+        assert_eq!(clamp(2.0), 0.25);
+        // crossed limits do not panic
+        assert_eq!(
+            run::<Clamp>(&[("input", 0.0), ("min", 1.0), ("max", -1.0)]),
+            -1.0
+        );
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(run::<Abs>(&[("input", -0.3)]), 0.3);
+        assert_eq!(run::<Multiply>(&[("a", -0.5), ("b", 0.5)]), -0.25);
+        assert_eq!(run::<Min>(&[("a", -0.5), ("b", 0.5)]), -0.5);
+        assert_eq!(run::<Max>(&[("a", -0.5), ("b", 0.5)]), 0.5);
+        // an unconnected input reads as 0
+        assert_eq!(run::<Max>(&[("a", -0.5), ("b", 0.0)]), 0.0);
+        // but has to be given, as the inputs of utility nodes are not `#[default]`
+        let missing = json!({ "inputs": { "a": constant(-0.5) }, "outputs": { "out": output(0) } });
+        assert!(serde_json::from_value::<Max>(missing).is_err());
+    }
+}
@@ -0,0 +1,532 @@
+use std::{collections::HashMap, fmt::Display};
+
+use serde::Serialize;
+
+use super::{
+    error::PatchError,
+    graph::execution_order,
+    serialized::{PatchDefinition, MAX_DELAY, MAX_LINKS, MAX_UNISON},
+};
+
+/// A problem found in a `PatchDefinition`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Index into `PatchDefinition::nodes` of the node at fault, or `None` if the problem is with
+    /// the patch IO or the patch as a whole.
+    pub node: Option<usize>,
+    #[serde(flatten)]
+    pub kind: DiagnosticKind,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// The patch can not be loaded.
+    Error,
+    /// The patch can be loaded but probably does not do what was intended.
+    Warning,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DiagnosticKind {
+    /// A port uses a link index that is not below `MAX_LINKS`.
+    LinkOutOfRange { port: String, link: usize },
+    /// A port reads a link delayed by more than `MAX_DELAY` samples.
+    DelayOutOfRange { port: String, delay: usize },
+    /// More than one output (or IO input) writes to the same link. `writers` holds the node
+    /// indices, with `None` standing for the patch IO.
+    MultipleWriters {
+        link: usize,
+        writers: Vec<Option<usize>>,
+    },
+    /// A port reads a link that nothing writes to, so it always reads zero.
+    UnwrittenLink { port: String, link: usize },
+    /// `lchan` or `rchan` is not connected, so that channel is silent.
+    MissingOutput { port: String },
+    /// A constant on an input port can not produce sensible output.
+    InvalidConstant {
+        port: String,
+        value: f64,
+        reason: String,
+    },
+    /// The links between these nodes form a cycle without any delayed links.
+    Cycle { nodes: Vec<usize> },
+    /// A value in `PatchDefinition::settings` can not be used.
+    InvalidSetting { setting: String, reason: String },
+}
+
+impl Diagnostic {
+    pub fn error(node: Option<usize>, kind: DiagnosticKind) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            node,
+            kind,
+        }
+    }
+
+    pub fn warning(node: Option<usize>, kind: DiagnosticKind) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            node,
+            kind,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error")?,
+            Severity::Warning => write!(f, "warning")?,
+        }
+        match self.node {
+            Some(node) => write!(f, " (node {}): ", node)?,
+            None => write!(f, " (io): ")?,
+        }
+        match &self.kind {
+            DiagnosticKind::LinkOutOfRange { port, link } => write!(
+                f,
+                "port {} uses link {}, link indices must be below {}",
+                port, link, MAX_LINKS
+            ),
+            DiagnosticKind::DelayOutOfRange { port, delay } => write!(
+                f,
+                "port {} is delayed by {} samples, delays must be at most {}",
+                port, delay, MAX_DELAY
+            ),
+            DiagnosticKind::MultipleWriters { link, writers } => {
+                write!(f, "link {} is written by more than one output: ", link)?;
+                let writers: Vec<String> = writers
+                    .iter()
+                    .map(|w| match w {
+                        Some(node) => format!("node {}", node),
+                        None => "io".to_string(),
+                    })
+                    .collect();
+                write!(f, "{}", writers.join(", "))
+            }
+            DiagnosticKind::UnwrittenLink { port, link } => {
+                write!(f, "port {} reads link {} which is never written", port, link)
+            }
+            DiagnosticKind::MissingOutput { port } => write!(f, "{} is not connected", port),
+            DiagnosticKind::InvalidConstant {
+                port,
+                value,
+                reason,
+            } => write!(f, "port {} has invalid value {}: {}", port, value, reason),
+            DiagnosticKind::Cycle { nodes } => write!(
+                f,
+                "nodes {:?} form a cycle, give one of the links a delay to make it a feedback loop",
+                nodes
+            ),
+            DiagnosticKind::InvalidSetting { setting, reason } => {
+                write!(f, "setting {} is invalid: {}", setting, reason)
+            }
+        }
+    }
+}
+
+impl PatchDefinition {
+    /// Check the patch for problems. The patch can only be loaded if none of the returned
+    /// diagnostics are errors.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+
+        // ports reading and writing links, as (node, port name, link)
+        let mut readers = Vec::new();
+        let mut writers = Vec::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let node = node.as_node();
+            for (name, port) in node.inputs() {
+                for (value, what) in [(port.mult(), "mult"), (port.bias(), "bias")] {
+                    if !value.is_finite() {
+                        diagnostics.push(Diagnostic::error(
+                            Some(i),
+                            DiagnosticKind::InvalidConstant {
+                                port: name.clone(),
+                                value,
+                                reason: format!("{} must be finite", what),
+                            },
+                        ));
+                    }
+                }
+                if let Some(delay) = port.delay().filter(|&delay| delay > MAX_DELAY) {
+                    diagnostics.push(Diagnostic::error(
+                        Some(i),
+                        DiagnosticKind::DelayOutOfRange {
+                            port: name.clone(),
+                            delay,
+                        },
+                    ));
+                }
+                if let Some(link) = port.link() {
+                    readers.push((Some(i), name, link));
+                }
+            }
+            for (name, port) in node.outputs() {
+                if let Some(link) = port.link() {
+                    writers.push((Some(i), name, link));
+                }
+            }
+            diagnostics.extend(
+                node.validate()
+                    .into_iter()
+                    .map(|kind| Diagnostic::error(Some(i), kind)),
+            );
+        }
+        for (name, link) in [("freq", self.io.freq), ("gate", self.io.gate)] {
+            if let Some(link) = link {
+                writers.push((None, name.to_string(), link));
+            }
+        }
+        for (name, link) in [("lchan", self.io.lchan), ("rchan", self.io.rchan)] {
+            match link {
+                Some(link) => readers.push((None, name.to_string(), link)),
+                None => diagnostics.push(Diagnostic::warning(
+                    None,
+                    DiagnosticKind::MissingOutput {
+                        port: name.to_string(),
+                    },
+                )),
+            }
+        }
+
+        for (node, port, link) in readers.iter().chain(writers.iter()) {
+            if *link >= MAX_LINKS {
+                diagnostics.push(Diagnostic::error(
+                    *node,
+                    DiagnosticKind::LinkOutOfRange {
+                        port: port.clone(),
+                        link: *link,
+                    },
+                ));
+            }
+        }
+
+        let mut link_writers: HashMap<usize, Vec<Option<usize>>> = HashMap::new();
+        for (node, _, link) in &writers {
+            link_writers.entry(*link).or_default().push(*node);
+        }
+        let mut multiple_writers: Vec<_> = link_writers
+            .iter()
+            .filter(|(_, w)| w.len() > 1)
+            .collect();
+        multiple_writers.sort_by_key(|(link, _)| **link);
+        for (&link, w) in multiple_writers {
+            diagnostics.push(Diagnostic::error(
+                w[1],
+                DiagnosticKind::MultipleWriters {
+                    link,
+                    writers: w.clone(),
+                },
+            ));
+        }
+
+        for (node, port, link) in readers {
+            if !link_writers.contains_key(&link) {
+                diagnostics.push(Diagnostic::warning(
+                    node,
+                    DiagnosticKind::UnwrittenLink { port, link },
+                ));
+            }
+        }
+
+        let unison = &self.settings.unison;
+        let too_many = format!("must be at most {}", MAX_UNISON);
+        for (setting, invalid, reason) in [
+            ("unison.count", unison.count == 0, "must be at least 1"),
+            ("unison.count", unison.count > MAX_UNISON, &too_many),
+            ("unison.detune", !unison.detune.is_finite(), "must be finite"),
+            ("unison.spread", !(0.0..=1.0).contains(&unison.spread), "must be between 0 and 1"),
+        ] {
+            if invalid {
+                diagnostics.push(Diagnostic::error(
+                    None,
+                    DiagnosticKind::InvalidSetting {
+                        setting: setting.to_string(),
+                        reason: reason.to_string(),
+                    },
+                ));
+            }
+        }
+
+        if let Some(envelope) = self.settings.amp_envelope {
+            let reason = match self.nodes.get(envelope) {
+                None => Some("there is no such node"),
+                Some(node) if node.as_node().is_idle().is_none() => Some("node is not an envelope"),
+                Some(_) => None,
+            };
+            if let Some(reason) = reason {
+                diagnostics.push(Diagnostic::error(
+                    Some(envelope).filter(|&i| i < self.nodes.len()),
+                    DiagnosticKind::InvalidSetting {
+                        setting: "amp_envelope".to_string(),
+                        reason: reason.to_string(),
+                    },
+                ));
+            }
+        }
+
+        if let Err(PatchError::Cycle(nodes)) = execution_order(self) {
+            diagnostics.push(Diagnostic::error(None, DiagnosticKind::Cycle { nodes }));
+        }
+
+        diagnostics
+    }
+
+    /// Validate the patch, returning all errors if it can not be loaded.
+    pub fn check(&self) -> Result<(), PatchError> {
+        let errors: Vec<Diagnostic> = self
+            .validate()
+            .into_iter()
+            .filter(Diagnostic::is_error)
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(PatchError::Invalid(errors))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::synth::port::PortUpdate;
+    use crate::synth::test_util::{constant, from_json, linked, output};
+    use crate::synth::Patch;
+
+    fn delayed(link: usize, delay: usize) -> Value {
+        let mut port = linked(link);
+        port["delay"] = json!(delay);
+        port
+    }
+
+    /// Oscillator with its phase modulated by `phase` and writing to `out`
+    fn osc(phase: Value, out: usize) -> Value {
+        json!({
+            "type": "sinosc",
+            "inputs": {
+                "freq": linked(0),
+                "phase": phase,
+                "vol": constant(1.0),
+                "feedback": constant(0.0),
+            },
+            "outputs": { "out": output(out) }
+        })
+    }
+
+    /// Patch playing link 1 on both channels
+    fn patch(nodes: Vec<Value>) -> PatchDefinition {
+        from_json(json!({
+            "nodes": nodes,
+            "io": { "freq": 0, "lchan": 1, "rchan": 1 }
+        }))
+    }
+
+    /// The only diagnostic of `def`
+    fn diagnostic(def: &PatchDefinition) -> Diagnostic {
+        let diagnostics = def.validate();
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        diagnostics[0].clone()
+    }
+
+    #[test]
+    fn multiple_writers() {
+        let def = patch(vec![osc(constant(0.0), 1), osc(constant(0.0), 1)]);
+        assert_eq!(
+            diagnostic(&def),
+            Diagnostic::error(
+                Some(1),
+                DiagnosticKind::MultipleWriters {
+                    link: 1,
+                    writers: vec![Some(0), Some(1)],
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn unwritten_link() {
+        let def = patch(vec![osc(linked(5), 1)]);
+        assert_eq!(
+            diagnostic(&def),
+            Diagnostic::warning(
+                Some(0),
+                DiagnosticKind::UnwrittenLink {
+                    port: "phase".to_string(),
+                    link: 5,
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn link_out_of_range() {
+        let def = patch(vec![osc(constant(0.0), 1), osc(constant(0.0), MAX_LINKS)]);
+        assert_eq!(
+            diagnostic(&def),
+            Diagnostic::error(
+                Some(1),
+                DiagnosticKind::LinkOutOfRange {
+                    port: "out".to_string(),
+                    link: MAX_LINKS,
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn missing_output() {
+        let def = from_json(json!({
+            "nodes": [osc(constant(0.0), 1)],
+            "io": { "freq": 0, "rchan": 1 }
+        }));
+        assert_eq!(
+            diagnostic(&def),
+            Diagnostic::warning(
+                None,
+                DiagnosticKind::MissingOutput {
+                    port: "lchan".to_string()
+                }
+            )
+        );
+
+        let def = from_json(json!({
+            "nodes": [osc(constant(0.0), 1)],
+            "io": { "freq": 0, "lchan": 1 }
+        }));
+        assert_eq!(
+            diagnostic(&def),
+            Diagnostic::warning(
+                None,
+                DiagnosticKind::MissingOutput {
+                    port: "rchan".to_string()
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn cycle() {
+        let def = patch(vec![osc(linked(2), 1), osc(linked(1), 2)]);
+        assert_eq!(
+            diagnostic(&def),
+            Diagnostic::error(None, DiagnosticKind::Cycle { nodes: vec![0, 1] })
+        );
+    }
+
+    #[test]
+    fn invalid_constant() {
+        let adsr = json!({
+            "type": "adsr",
+            "inputs": {
+                "gate": linked(0),
+                "a": constant(0.0),
+                "d": constant(0.1),
+                "s": constant(0.5),
+                "r": constant(0.1),
+            },
+            "outputs": { "out": output(1) }
+        });
+        assert_eq!(
+            diagnostic(&patch(vec![adsr])),
+            Diagnostic::error(
+                Some(0),
+                DiagnosticKind::InvalidConstant {
+                    port: "a".to_string(),
+                    value: 0.0,
+                    reason: "attack time must be positive".to_string(),
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn non_finite_constant() {
+        // JSON has no infinity, so set it after loading like a port update would
+        let mut def = patch(vec![osc(constant(0.0), 1)]);
+        let update = PortUpdate {
+            node: 0,
+            port: "vol".to_string(),
+            mult: Some(f64::INFINITY),
+            bias: Some(f64::NAN),
+        };
+        let node = def.nodes[0].as_node_mut();
+        node.input_mut("vol").unwrap().apply(&update);
+
+        let diagnostics = def.validate();
+        assert_eq!(diagnostics.len(), 2, "{:?}", diagnostics);
+        assert_eq!(
+            diagnostics[0],
+            Diagnostic::error(
+                Some(0),
+                DiagnosticKind::InvalidConstant {
+                    port: "vol".to_string(),
+                    value: f64::INFINITY,
+                    reason: "mult must be finite".to_string(),
+                }
+            )
+        );
+        // NaN is not equal to itself, so compare the rest of the diagnostic
+        assert!(diagnostics[1].is_error());
+        assert_eq!(diagnostics[1].node, Some(0));
+        assert!(matches!(
+            &diagnostics[1].kind,
+            DiagnosticKind::InvalidConstant { port, value, reason }
+                if port == "vol" && value.is_nan() && reason == "bias must be finite"
+        ));
+    }
+
+    #[test]
+    fn unison_count_out_of_range() {
+        let mut def = patch(vec![osc(constant(0.0), 1)]);
+        def.settings.unison.count = 1_000_000_000_000;
+        assert_eq!(
+            diagnostic(&def),
+            Diagnostic::error(
+                None,
+                DiagnosticKind::InvalidSetting {
+                    setting: "unison.count".to_string(),
+                    reason: format!("must be at most {}", MAX_UNISON),
+                }
+            )
+        );
+        // rejected before the copies are created
+        assert!(Patch::new(&def, 1).is_err());
+
+        def.settings.unison.count = MAX_UNISON;
+        assert_eq!(def.validate(), vec![]);
+    }
+
+    #[test]
+    fn valid_patch() {
+        let def = patch(vec![osc(delayed(1, MAX_DELAY), 1)]);
+        assert_eq!(def.validate(), vec![]);
+    }
+
+    #[test]
+    fn delay_out_of_range() {
+        let def = patch(vec![osc(delayed(1, 1_000_000_000_000), 1)]);
+        assert_eq!(
+            diagnostic(&def),
+            Diagnostic::error(
+                Some(0),
+                DiagnosticKind::DelayOutOfRange {
+                    port: "phase".to_string(),
+                    delay: 1_000_000_000_000,
+                }
+            )
+        );
+        // rejected before the delay buffer is allocated
+        assert!(Patch::new(&def, 1).is_err());
+    }
+}
@@ -3,49 +3,128 @@ use std::fmt::Display;
 use crate::synth::dsp_node::DspNode;
 
 use super::{
-    serialized::{DspNodeEnum, IO, PatchDefinition}, SynthInputEvent,
+    error::PatchError,
+    graph::execution_order,
+    port::{Port, PortUpdate},
+    serialized::{PatchDefinition, IO, MAX_DELAY},
+    SynthInputEvent, SAMPLE_RATE,
 };
 
+/// Output below this level counts as silence, see `Program::is_silent` (-100dB).
+const SILENCE_THRESHOLD: f64 = 1e-5;
+/// Number of silent frames after which a program without an amp envelope counts as silent
+/// (10ms). Long enough that an audible oscillation can not pass for silence.
+const SILENCE_FRAMES: usize = SAMPLE_RATE as usize / 100;
+
 pub struct Program {
     state: ProgramState,
+    // nodes in the same order as `PatchDefinition::nodes`
     nodes: Vec<Box<dyn DspNode + Send>>,
+    // indices into `nodes` in dependency order
+    order: Vec<usize>,
     io: IO,
-    // used for LR interlacing
-    pending_sample: Option<f64>,
+    // envelope nodes which must be idle for the program to be silent
+    envelopes: Vec<usize>,
+    // whether `envelopes` is the designated amp envelope, which is enough on its own
+    amp_envelope: bool,
+    // consecutive frames with all `envelopes` idle and output below `SILENCE_THRESHOLD`
+    silent_frames: usize,
 }
 
 pub struct ProgramState {
     pub links: Vec<f64>,
+    /// When set, envelopes output a constant 1 instead of following the gate. Used to draw
+    /// waveforms.
+    pub bypass_envelopes: bool,
+    // past values of links which are read through delayed ports, indexed by link
+    history: Vec<Option<LinkHistory>>,
+}
+
+/// Ring buffer holding the most recent values of a single link.
+struct LinkHistory {
+    samples: Vec<f64>,
+    // position of the next write, which is also the oldest stored sample
+    pos: usize,
 }
 
 impl ProgramState {
     pub fn new(num_links: usize) -> Self {
         ProgramState {
             links: vec![0.0; num_links],
+            bypass_envelopes: false,
+            history: (0..num_links).map(|_| None).collect(),
+        }
+    }
+
+    /// Ensure at least `delay` past samples of `link` are remembered.
+    pub fn add_history(&mut self, link: usize, delay: usize) {
+        match &mut self.history[link] {
+            Some(history) if history.samples.len() >= delay => {}
+            history => {
+                *history = Some(LinkHistory {
+                    samples: vec![0.0; delay],
+                    pos: 0,
+                })
+            }
+        }
+    }
+
+    /// Value of `link` at the end of the sample `delay` samples ago. `delay` must be at least 1
+    /// and no larger than the history registered with `add_history`.
+    #[inline]
+    pub fn delayed(&self, link: usize, delay: usize) -> f64 {
+        let history = self.history[link]
+            .as_ref()
+            .expect("delayed read from link without history");
+        let len = history.samples.len();
+        history.samples[(history.pos + len - delay) % len]
+    }
+
+    /// Push the current value of every link with a history. Called once at the end of every
+    /// sample.
+    fn record_history(&mut self) {
+        for (link, history) in self.history.iter_mut().enumerate() {
+            if let Some(history) = history {
+                history.samples[history.pos] = self.links[link];
+                history.pos = (history.pos + 1) % history.samples.len();
+            }
         }
     }
 }
 
 impl Program {
-    pub fn new(def: &PatchDefinition) -> Self {
-        // map enum into trait object
-        let dyn_nodes: Vec<Box<dyn DspNode + Send>> = def
-            .nodes
-            .iter()
-            .map(|x| -> Box<dyn DspNode + Send> {
-                match x {
-                    DspNodeEnum::Adsr(x) => Box::new(x.clone()) as _,
-                    DspNodeEnum::SinOsc(x) => Box::new(x.clone()) as _,
-                    DspNodeEnum::Mixer(x) => Box::new(x.clone()) as _,
+    pub fn new(def: &PatchDefinition) -> Result<Self, PatchError> {
+        let mut state = ProgramState::new(def.num_links()?);
+        for (i, node) in def.nodes.iter().enumerate() {
+            for (_, port) in node.as_node().inputs() {
+                if let (Some(link), Some(delay)) = (port.link(), port.delay()) {
+                    // checked before allocating, as the history of every voice holds `delay`
+                    // samples
+                    if delay > MAX_DELAY {
+                        return Err(PatchError::DelayOutOfRange { node: i, delay });
+                    }
+                    state.add_history(link, delay);
                 }
-            })
-            .collect();
-        Program {
-            state: ProgramState::new(100),
-            nodes: dyn_nodes,
-            io: def.io.clone(),
-            pending_sample: None,
+            }
         }
+
+        let envelopes = match def.settings.amp_envelope {
+            Some(envelope) => vec![envelope],
+            None => (0..def.nodes.len())
+                .filter(|&i| def.nodes[i].as_node().is_idle().is_some())
+                .collect(),
+        };
+
+        Ok(Program {
+            state,
+            // map enum into trait object
+            nodes: def.nodes.iter().map(|x| x.to_boxed()).collect(),
+            order: execution_order(def)?,
+            io: def.io.clone(),
+            envelopes,
+            amp_envelope: def.settings.amp_envelope.is_some(),
+            silent_frames: 0,
+        })
     }
 
     pub fn set_freq(&mut self, freq: f64) {
@@ -72,34 +151,71 @@ impl Program {
         };
     }
 
+    /// Hold all envelopes at 1, see `ProgramState::bypass_envelopes`.
+    pub fn bypass_envelopes(&mut self) {
+        self.state.bypass_envelopes = true;
+    }
+
+    /// Current value of each connected output of the node at `node` (an index into
+    /// `PatchDefinition::nodes`).
+    pub fn node_outputs(&self, node: usize) -> Vec<(String, f64)> {
+        self.nodes[node]
+            .outputs()
+            .into_iter()
+            .filter(|(_, port)| port.link().is_some())
+            .map(|(name, port)| (name, port.read(&self.state)))
+            .collect()
+    }
+
+    /// Apply `update` to the node it addresses, keeping all other state. Returns false if the
+    /// port does not exist.
+    pub fn update_port(&mut self, update: &PortUpdate) -> bool {
+        match self
+            .nodes
+            .get_mut(update.node)
+            .and_then(|n| n.input_mut(&update.port))
+        {
+            Some(port) => {
+                port.apply(update);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the program has stopped making sound, so it does not need to run until the next
+    /// key down. Only meaningful once the gate is off.
+    pub fn is_silent(&self) -> bool {
+        if self.amp_envelope {
+            self.silent_frames > 0
+        } else {
+            self.silent_frames >= SILENCE_FRAMES
+        }
+    }
+
     pub fn next_sample(&mut self) -> (f64, f64) {
-        for node in &mut self.nodes {
-            node.next_sample(&mut self.state);
+        for &i in &self.order {
+            self.nodes[i].next_sample(&mut self.state);
         }
+        self.state.record_history();
 
-        (
+        let out = (
             self.io.lchan.map(|i| self.state.links[i]).unwrap_or(0.0),
             self.io.rchan.map(|i| self.state.links[i]).unwrap_or(0.0),
-        )
-    }
-}
+        );
 
-impl Iterator for Program {
-    type Item = f32;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        // Perform interlacing
-        if let Some(samp) = self.pending_sample {
-            // If a sample was pending, send that out and perform no extra computation
-            self.pending_sample = None;
-            Some(samp as f32)
+        let envelopes_idle = self
+            .envelopes
+            .iter()
+            .all(|&i| self.nodes[i].is_idle() == Some(true));
+        let quiet = self.amp_envelope
+            || (out.0.abs() < SILENCE_THRESHOLD && out.1.abs() < SILENCE_THRESHOLD);
+        if envelopes_idle && quiet {
+            self.silent_frames += 1;
         } else {
-            // No pending sample, so generate a new left/right pair.
-            // Send left immediately, store right in pending_sample
-            let (l, r) = self.next_sample();
-            self.pending_sample = Some(r);
-            Some(l as f32)
+            self.silent_frames = 0;
         }
+        out
     }
 }
 
@@ -109,3 +225,96 @@ impl Display for Program {
         write!(f, "program TODO")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::synth::{
+        test_util::{constant, from_json, linked, output},
+        Patch,
+    };
+
+    /// Sine with a 10ms release envelope on the left, and a drone at `drone` volume with no
+    /// envelope on the right
+    fn patch(settings: Value, drone: f64) -> PatchDefinition {
+        from_json(json!({
+            "nodes": [{
+                "type": "sinosc",
+                "inputs": {
+                    "freq": linked(0),
+                    "phase": constant(0.0),
+                    "vol": linked(2),
+                    "feedback": constant(0.0),
+                },
+                "outputs": { "out": output(3) }
+            }, {
+                "type": "adsr",
+                "inputs": {
+                    "gate": linked(1),
+                    "a": constant(0.001),
+                    "d": constant(0.0),
+                    "s": constant(1.0),
+                    "r": constant(0.01),
+                },
+                "outputs": { "out": output(2) }
+            }, {
+                "type": "sinosc",
+                "inputs": {
+                    "freq": constant(0.1),
+                    "phase": constant(0.0),
+                    "vol": constant(drone),
+                    "feedback": constant(0.0),
+                },
+                "outputs": { "out": output(4) }
+            }],
+            "io": { "freq": 0, "gate": 1, "lchan": 3, "rchan": 4 },
+            "settings": settings
+        }))
+    }
+
+    /// Play a note for `held` samples, returning how many samples after the release the program
+    /// went silent
+    fn frames_until_silent(def: &PatchDefinition, held: usize) -> Option<usize> {
+        let mut program = Program::new(def).unwrap();
+        program.process_event(SynthInputEvent::key_down(69));
+        for _ in 0..held {
+            program.next_sample();
+        }
+        assert!(!program.is_silent());
+        program.process_event(SynthInputEvent::KeyUp { key: 69 });
+        (0..SAMPLE_RATE as usize).find(|_| {
+            program.next_sample();
+            program.is_silent()
+        })
+    }
+
+    #[test]
+    fn amp_envelope_decides_silence() {
+        // the envelope is idle after its 10ms release, the quiet drone on the right is ignored
+        let frames = frames_until_silent(&patch(json!({ "amp_envelope": 1 }), 1e-3), 1000).unwrap();
+        assert!((440..450).contains(&frames), "{}", frames);
+    }
+
+    #[test]
+    fn output_level_decides_silence_without_amp_envelope() {
+        assert_eq!(frames_until_silent(&patch(json!({}), 1e-3), 1000), None);
+        // release, then 10ms of silence
+        let frames = frames_until_silent(&patch(json!({}), 0.0), 1000).unwrap();
+        assert!((880..890).contains(&frames), "{}", frames);
+    }
+
+    #[test]
+    fn note_released_during_attack_goes_silent() {
+        // released halfway through the 1ms attack, the envelope still has to run its release
+        let frames = frames_until_silent(&patch(json!({ "amp_envelope": 1 }), 0.0), 22).unwrap();
+        assert!((440..450).contains(&frames), "{}", frames);
+    }
+
+    #[test]
+    fn amp_envelope_must_be_an_envelope() {
+        assert!(Patch::new(&patch(json!({ "amp_envelope": 0 }), 0.0), 1).is_err());
+        assert!(Patch::new(&patch(json!({ "amp_envelope": 3 }), 0.0), 1).is_err());
+    }
+}
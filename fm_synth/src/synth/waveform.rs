@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use super::{voice::Program, PatchDefinition, PatchError, SAMPLE_RATE};
+
+/// Frequency of the note used to compute waveforms, low enough that one cycle has plenty of
+/// samples to draw.
+pub const WAVEFORM_FREQ: f64 = 110.0;
+
+/// The output of every node of a patch over one cycle of a note.
+#[derive(Serialize, Debug)]
+pub struct Waveforms {
+    /// Frequency of the note that was played.
+    pub freq: f64,
+    /// One entry per node, in the same order as `PatchDefinition::nodes`.
+    pub nodes: Vec<NodeWaveform>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NodeWaveform {
+    /// Samples of each connected output, by port name.
+    pub outputs: BTreeMap<String, Vec<f32>>,
+}
+
+/// Compute one cycle of every node's output while a note of `freq` is held. Envelopes are
+/// bypassed (held at 1) so the shapes are those of the sustained sound. The patch runs for one
+/// cycle before recording to let feedback settle.
+pub fn waveforms(def: &PatchDefinition, freq: f64) -> Result<Waveforms, PatchError> {
+    let mut program = Program::new(def)?;
+    program.bypass_envelopes();
+    program.set_freq(freq);
+    program.set_gate(true);
+
+    let cycle = (SAMPLE_RATE as f64 / freq).round() as usize;
+    for _ in 0..cycle {
+        program.next_sample();
+    }
+
+    let mut nodes: Vec<NodeWaveform> = (0..def.nodes.len())
+        .map(|_| NodeWaveform {
+            outputs: BTreeMap::new(),
+        })
+        .collect();
+    for _ in 0..cycle {
+        program.next_sample();
+        for (i, node) in nodes.iter_mut().enumerate() {
+            for (name, value) in program.node_outputs(i) {
+                node.outputs.entry(name).or_default().push(value as f32);
+            }
+        }
+    }
+
+    Ok(Waveforms { freq, nodes })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::synth::test_util::{constant, from_json, linked, output};
+
+    #[test]
+    fn envelope_is_bypassed_and_one_cycle_is_recorded() {
+        // sine with a slow attack, which would be near silent without the bypass
+        let def: PatchDefinition = from_json(json!({
+            "nodes": [{
+                "type": "sinosc",
+                "inputs": {
+                    "freq": linked(0),
+                    "phase": constant(0.0),
+                    "vol": linked(2),
+                    "feedback": constant(0.0),
+                },
+                "outputs": { "out": output(3) }
+            }, {
+                "type": "adsr",
+                "inputs": {
+                    "gate": linked(1),
+                    "a": constant(10.0),
+                    "d": constant(0.1),
+                    "s": constant(0.5),
+                    "r": constant(0.1),
+                },
+                "outputs": { "out": output(2) }
+            }],
+            "io": { "freq": 0, "gate": 1, "lchan": 3, "rchan": 3 }
+        }));
+
+        let w = waveforms(&def, WAVEFORM_FREQ).unwrap();
+        assert_eq!(w.nodes.len(), 2);
+        let cycle = (SAMPLE_RATE as f64 / WAVEFORM_FREQ).round() as usize;
+
+        let env = &w.nodes[1].outputs["out"];
+        assert_eq!(env.len(), cycle);
+        assert!(env.iter().all(|&v| v == 1.0));
+
+        let osc = &w.nodes[0].outputs["out"];
+        assert_eq!(osc.len(), cycle);
+        let peak = osc.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+        assert!(peak > 0.99, "peak {}", peak);
+    }
+}
@@ -0,0 +1,145 @@
+//! Golden audio regression tests. Each test renders a patch from `testdata/patches` and compares
+//! the result against a reference recording in `testdata/golden`. To (re)generate the references
+//! after an intended change in sound, run the tests with `FM_BLESS=1`.
+
+use std::{env, fs, path::PathBuf};
+
+use fm::synth::{
+    render, Patch, PatchDefinition, SynthInputEvent, TimedEvent, DEFAULT_VOICES, SAMPLE_RATE,
+};
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+
+/// Largest allowed difference of any sample from the reference. References are stored as 16 bit
+/// PCM, so this must be well above the quantization step.
+const TOLERANCE: f32 = 1e-3;
+
+fn testdata(path: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata")
+        .join(path)
+}
+
+fn seconds(s: f64) -> u64 {
+    (s * SAMPLE_RATE as f64) as u64
+}
+
+/// Events holding each `(key, start, end)` note, with times in seconds.
+fn notes(notes: &[(u8, f64, f64)]) -> Vec<TimedEvent> {
+    let mut events = Vec::new();
+    for &(key, start, end) in notes {
+        events.push(TimedEvent {
+            sample: seconds(start),
+            event: SynthInputEvent::key_down(key),
+        });
+        events.push(TimedEvent {
+            sample: seconds(end),
+            event: SynthInputEvent::KeyUp { key },
+        });
+    }
+    events.sort_by_key(|e| e.sample);
+    events
+}
+
+/// Render `testdata/patches/<patch>.json` with `events` for `length` seconds.
+fn render_patch(patch: &str, events: &[TimedEvent], length: f64) -> Vec<f32> {
+    let text = fs::read_to_string(testdata(&format!("patches/{}.json", patch))).unwrap();
+    let def: PatchDefinition = serde_json::from_str(&text).unwrap();
+    let mut patch = Patch::new(&def, DEFAULT_VOICES).unwrap();
+    render(&mut patch, events, seconds(length))
+}
+
+/// Compare `samples` against the reference recording `testdata/golden/<name>.wav`.
+fn assert_golden(name: &str, samples: &[f32]) {
+    let path = testdata(&format!("golden/{}.wav", name));
+
+    if env::var_os("FM_BLESS").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for &sample in samples {
+            writer
+                .write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16)
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+        return;
+    }
+
+    let reference: Vec<f32> = WavReader::open(&path)
+        .unwrap_or_else(|e| {
+            panic!(
+                "could not open {}: {}, run with FM_BLESS=1 to create it",
+                path.display(),
+                e
+            )
+        })
+        .samples::<i16>()
+        .map(|s| s.unwrap() as f32 / i16::MAX as f32)
+        .collect();
+
+    assert_eq!(
+        samples.len(),
+        reference.len(),
+        "{}: length differs from reference",
+        name
+    );
+    let (worst, error) = samples
+        .iter()
+        .zip(&reference)
+        .map(|(a, b)| (a - b).abs())
+        .enumerate()
+        .fold((0, 0.0), |worst, (i, e)| if e > worst.1 { (i, e) } else { worst });
+    assert!(
+        error <= TOLERANCE,
+        "{}: sample {} (frame {}) differs from reference by {}",
+        name,
+        worst,
+        worst / 2,
+        error
+    );
+}
+
+#[test]
+fn sine_envelope() {
+    let samples = render_patch("sine", &notes(&[(69, 0.0, 0.1)]), 0.25);
+    assert_golden("sine", &samples);
+}
+
+#[test]
+fn fm_chord() {
+    let events = notes(&[(60, 0.0, 0.15), (64, 0.05, 0.15), (67, 0.1, 0.2)]);
+    let samples = render_patch("fm", &events, 0.3);
+    assert_golden("fm", &samples);
+}
+
+#[test]
+fn cross_feedback() {
+    let samples = render_patch("cross_feedback", &notes(&[(57, 0.0, 0.2)]), 0.25);
+    assert_golden("cross_feedback", &samples);
+}
+
+/// Three detuned copies of the sine patch spread across the stereo field.
+#[test]
+fn unison() {
+    let samples = render_patch("unison", &notes(&[(57, 0.0, 0.5)]), 0.7);
+    assert_golden("unison", &samples);
+
+    // lowest copy is hard left, highest hard right, so the channels differ
+    let (left, right): (Vec<f32>, Vec<f32>) = samples.chunks(2).map(|f| (f[0], f[1])).unzip();
+    assert_ne!(left, right);
+}
+
+#[test]
+fn node_order_does_not_change_output() {
+    // the same patch as `fm`, with the carrier listed before the modulator
+    let events = notes(&[(60, 0.0, 0.15), (64, 0.05, 0.15), (67, 0.1, 0.2)]);
+    assert_eq!(
+        render_patch("fm_reordered", &events, 0.3),
+        render_patch("fm", &events, 0.3)
+    );
+}